@@ -0,0 +1,24 @@
+//! Feeds arbitrary bytes to the packet parser, like a hostile peer would.
+//! Run with `cargo +nightly fuzz run parse` in the repository root.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sfshare::transport::{self, Limits};
+use std::io::BufReader;
+
+fuzz_target!(|data: &[u8]| {
+    // small limits, so the fuzzer hits them instead of waiting for megabytes
+    let limits = Limits {
+        max_entries: 1000,
+        max_name_len: 1000,
+        max_block_size: 64 * 1024,
+        max_total_size: 1 << 40,
+    };
+    let mut reader = BufReader::new(data);
+    while let Ok(packet) = transport::parse_with(&mut reader, &limits) {
+        // whatever parses writes back to the same packet
+        let buf = packet.to_buf();
+        let again = transport::parse_with(&mut BufReader::new(&buf[..]), &limits).expect("written packet doesn't parse");
+        assert_eq!(again, packet);
+    }
+});
@@ -0,0 +1,143 @@
+//! Throughput of the file block sending over loopback, `sfshare bench`.
+//!
+//! Compares the old loop (1300 byte blocks, new allocations per block) with the adaptive
+//! blocks, with and without encryption. Hashing is left out, only the transport gets measured.
+
+use sfshare::crypto::{self, SecureReader, SecureWriter};
+use sfshare::send;
+use sfshare::transport::{self, Parsed};
+use sfshare::{Error, Result};
+
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, TcpListener, TcpStream};
+use std::path::Path;
+use std::thread;
+use std::time::Instant;
+
+#[derive(Clone, Copy)]
+enum Mode {
+    /// the block loop before adaptive blocks
+    Legacy,
+    Adaptive,
+}
+
+fn legacy_send<W: Write>(file: &mut File, size: u64, stream: &mut W) -> io::Result<()> {
+    let mut reader = BufReader::new(file);
+    let mut send = 0;
+    while send < size {
+        let n = (size - send).min(1300) as usize;
+        let mut data = vec![0u8; n];
+        reader.read_exact(&mut data)?;
+
+        let packet = Parsed::FileBlock { id: 0, data };
+        stream.write_all(packet.to_buf().as_ref())?;
+        send += n as u64;
+    }
+    Ok(())
+}
+
+/// sends `path` over a loopback connection, returns the seconds it took until all blocks arrived
+fn run_case(path: &Path, size: u64, mode: Mode, encrypt: bool) -> io::Result<f64> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    let addr = listener.local_addr()?;
+
+    let (send_cipher, recv_cipher) = if encrypt {
+        let sender = crypto::Handshake::new();
+        let receiver = crypto::Handshake::new();
+        let sender_pub = sender.public_key();
+        let s_keys = sender.finish(crypto::Role::Sender, receiver.public_key())?;
+        let r_keys = receiver.finish(crypto::Role::Receiver, sender_pub)?;
+        (Some(s_keys.send), Some(r_keys.recv))
+    } else {
+        (None, None)
+    };
+
+    let receiver = thread::spawn(move || -> io::Result<u64> {
+        let (tcp, _) = listener.accept()?;
+        let mut reader = BufReader::new(SecureReader::new(tcp));
+        if let Some(cipher) = recv_cipher {
+            crypto::start_decryption(&mut reader, cipher)?;
+        }
+
+        let mut received = 0;
+        loop {
+            match transport::parse(&mut reader) {
+                Ok(Parsed::FileBlock { data, .. }) => received += data.len() as u64,
+                Ok(p) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("unexpected packet {:?}", p),
+                    ))
+                }
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(received),
+                Err(e) => return Err(e),
+            }
+        }
+    });
+
+    let start = Instant::now();
+    let tcp = TcpStream::connect(addr)?;
+    let mut stream = SecureWriter::new(tcp.try_clone()?);
+    if let Some(cipher) = send_cipher {
+        stream.start_encryption(cipher);
+    }
+
+    let mut file = File::open(path)?;
+    match mode {
+        Mode::Legacy => legacy_send(&mut file, size, &mut stream)?,
+        Mode::Adaptive => send::send_blocks(&mut file, 0, size, None, None, &mut stream, &mut |_| Ok(()))?,
+    }
+    stream.flush()?;
+    tcp.shutdown(Shutdown::Write)?;
+
+    let received = receiver
+        .join()
+        .map_err(|_| io::Error::other("receiver thread panicked"))??;
+    let secs = start.elapsed().as_secs_f64();
+
+    if received != size {
+        return Err(io::Error::other(format!("received {} of {} bytes", received, size)));
+    }
+    Ok(secs)
+}
+
+pub fn run(size_mb: u64) -> Result<()> {
+    let size = size_mb
+        .checked_mul(1_000_000)
+        .ok_or_else(|| Error::Usage(format!("{} mb are too large for the test file", size_mb)))?;
+    let path = std::env::temp_dir().join("sfshare_bench.dat");
+
+    // not compressible, like most large files
+    let mut data = vec![0u8; 1_000_000];
+    let mut x: u64 = 0x9e37_79b9_7f4a_7c15;
+    for b in data.iter_mut() {
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        *b = x as u8;
+    }
+    let mut f = File::create(&path)?;
+    for _ in 0..size_mb {
+        f.write_all(&data)?;
+    }
+    drop(f);
+
+    let fast_path = if cfg!(target_os = "linux") { " (sendfile)" } else { "" };
+    let cases = [
+        (String::from("1300 byte blocks, plain (old)"), Mode::Legacy, false),
+        (format!("adaptive blocks, plain{}", fast_path), Mode::Adaptive, false),
+        (String::from("1300 byte blocks, encrypted (old)"), Mode::Legacy, true),
+        (String::from("adaptive blocks, encrypted"), Mode::Adaptive, true),
+    ];
+
+    for (name, mode, encrypt) in cases.iter() {
+        let res = run_case(&path, size, *mode, *encrypt);
+        match res {
+            Ok(secs) => println!("{:40} {:9.1} mb/s", name, size as f64 / 1_000_000.0 / secs),
+            Err(e) => eprintln!("{:40} failed: {}", name, e),
+        }
+    }
+
+    Ok(std::fs::remove_file(&path)?)
+}
@@ -0,0 +1,199 @@
+use clap::{CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
+
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv6Addr};
+use std::path::PathBuf;
+
+use sfshare::compress::Compression;
+use sfshare::paths::Conflict;
+use sfshare::{send, transport, Endpoint, Error, Result};
+
+use crate::{term, AppState};
+
+/// Simple file sharing in the local network
+#[derive(Parser, Debug)]
+#[command(name = "sfshare", version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Send files and directories to a receiver
+    Send {
+        /// ip, ip:port, [ipv6]:port, hostname[:port], device name or unix:<path> of the receiver.
+        /// not given with --via, all arguments are files then
+        #[arg(required_unless_present = "via")]
+        to: Option<String>,
+        /// files, directories or glob patterns to send
+        #[arg(required_unless_present = "via")]
+        files: Vec<String>,
+        /// connect through the stdin/stdout of this command instead,
+        /// e.g. "ssh host sfshare recv --stdio --yes"
+        #[arg(long, value_name = "COMMAND")]
+        via: Option<String>,
+        /// send large files over this many parallel connections
+        #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u8).range(1..=32))]
+        streams: u8,
+        /// don't encrypt the connection, the receiver has to allow it
+        #[arg(long)]
+        no_encryption: bool,
+        /// compress file blocks, compressed file types and incompressible data get send raw
+        #[arg(long, value_enum, default_value_t = Compression::None)]
+        compress: Compression,
+    },
+    /// Wait for files
+    Recv {
+        /// device name senders can use instead of the ip (defaults to the hostname)
+        name: Option<String>,
+        /// only listen on this address, default is all interfaces (IPv4 and IPv6)
+        #[arg(long, default_value_t = IpAddr::V6(Ipv6Addr::UNSPECIFIED))]
+        bind: IpAddr,
+        /// tcp port to listen on
+        #[arg(long, default_value_t = transport::DEFAULT_PORT)]
+        port: u16,
+        /// listen on a unix domain socket at this path instead of tcp
+        #[arg(long, value_name = "PATH", conflicts_with_all = ["bind", "port"])]
+        unix: Option<PathBuf>,
+        /// receive a single request over stdin/stdout (for inetd, socat, ...),
+        /// requests get asked on the terminal if there is one, otherwise they need --yes
+        #[arg(long, conflicts_with_all = ["bind", "port", "unix"])]
+        stdio: bool,
+        /// accept requests without asking (if they pass the other rules)
+        #[arg(short, long)]
+        yes: bool,
+        /// only accept requests from this address, can be repeated
+        #[arg(long, value_name = "IP")]
+        allow: Vec<IpAddr>,
+        /// reject requests larger than this many mb
+        #[arg(long, value_name = "MB")]
+        max_size: Option<u64>,
+        /// reject requests with more files
+        #[arg(long, value_name = "COUNT")]
+        max_files: Option<usize>,
+        /// accept requests over unencrypted connections (faster, only for trusted networks)
+        #[arg(long)]
+        allow_unencrypted: bool,
+        /// what to do with files that exist already
+        #[arg(long, value_enum, value_name = "ACTION", default_value_t = Conflict::Rename)]
+        on_conflict: Conflict,
+        /// store received files in this directory (created if missing)
+        #[arg(short, long, value_name = "DIR", default_value = ".")]
+        out_dir: PathBuf,
+        /// remove the .part files of interrupted transmissions instead of keeping them to resume
+        #[arg(long)]
+        discard_partial: bool,
+    },
+    /// Show receivers in the local network
+    List,
+    /// Generate a test file
+    Testgen {
+        file: PathBuf,
+        /// size in mb
+        size: u64,
+    },
+    /// Measure the throughput of sending files over loopback
+    Bench {
+        /// size of the test file in mb
+        #[arg(default_value_t = 256)]
+        size: u64,
+    },
+    /// Print shell completions
+    Completions { shell: Shell },
+}
+
+pub fn match_send(
+    to: Option<String>,
+    via: Option<String>,
+    files: &[String],
+    streams: usize,
+    encrypt: bool,
+    compression: Compression,
+) -> Result<AppState> {
+    let through_command = via.is_some();
+    let (to, patterns): (Endpoint, Vec<String>) = match (via, to) {
+        // there's no receiver argument then, clap puts the first file into `to`
+        (Some(command), to) => (Endpoint::Command(command), to.into_iter().chain(files.iter().cloned()).collect()),
+        (None, Some(to)) => (send::resolve_target(&to, &term::send_events())?, files.to_vec()),
+        (None, None) => return Err(Error::Usage(String::from("no receiver given"))),
+    };
+    if through_command && patterns.is_empty() {
+        return Err(Error::Usage(String::from("no files given, --via needs the files to send")));
+    }
+
+    let mut files_to_send: HashSet<PathBuf> = HashSet::with_capacity(patterns.len());
+
+    for pattern in &patterns {
+        let entries = glob::glob(pattern).map_err(|e| Error::Usage(format!("Invalid pattern {:?}: {}", pattern, e)))?;
+        let mut matched = false;
+        for entry in entries {
+            match entry {
+                Ok(pbuf) => { files_to_send.insert(pbuf); matched = true; },
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+        // most likely an address out of habit, sending the other files to wherever the command leads is wrong
+        if through_command && !matched {
+            return Err(Error::Usage(format!(
+                "{:?} matches no file, with --via the command reaches the receiver and all arguments are files",
+                pattern
+            )));
+        }
+    }
+
+    Ok(AppState::Send {
+        to,
+        files: files_to_send.drain().collect(),
+        streams,
+        encrypt,
+        compression,
+    })
+}
+
+pub fn print_completions(shell: Shell) {
+    let mut cmd = Cli::command();
+    let name = cmd.get_name().to_string();
+    clap_complete::generate(shell, &mut cmd, name, &mut std::io::stdout());
+}
+
+#[test]
+fn test_cli_parse() {
+    let cli = Cli::try_parse_from(["sfshare", "send", "recv-box:6000", "recv", "a.txt"]).unwrap();
+    match cli.command {
+        Command::Send {
+            to, files, streams, ..
+        } => {
+            assert_eq!(to.as_deref(), Some("recv-box:6000"));
+            // a file named like a subcommand is just a file
+            assert_eq!(files, vec!["recv", "a.txt"]);
+            assert_eq!(streams, 1);
+        }
+        c => panic!("parsed {:?}", c),
+    }
+
+    assert!(Cli::try_parse_from(["sfshare", "send", "::1"]).is_err());
+    assert!(Cli::try_parse_from(["sfshare", "send", "--streams", "0", "::1", "a"]).is_err());
+    assert!(Cli::try_parse_from(["sfshare", "recv", "--unix", "/tmp/s", "--port", "6000"]).is_err());
+    assert!(Cli::try_parse_from(["sfshare", "recv", "--stdio", "--yes"]).is_ok());
+    assert!(Cli::try_parse_from(["sfshare", "send"]).is_err());
+
+    // with --via every argument is a file
+    let send = |args: &[&str]| match Cli::try_parse_from([&["sfshare", "send", "--via", "cat"], args].concat()) {
+        Ok(Cli { command: Command::Send { to, via, files, .. } }) => match_send(to, via, &files, 1, true, Compression::None),
+        r => panic!("parsed {:?}", r),
+    };
+    let manifest = concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml");
+    match send(&[manifest]) {
+        Ok(AppState::Send { to, files, .. }) => {
+            assert_eq!(to, Endpoint::Command(String::from("cat")));
+            assert_eq!(files, vec![PathBuf::from(manifest)]);
+        }
+        _ => panic!("--via with a file"),
+    }
+    assert!(matches!(send(&[]), Err(Error::Usage(_))));
+    // an address isn't sent as a file
+    assert!(matches!(send(&["10.0.0.2", manifest]), Err(Error::Usage(_))));
+    Cli::command().debug_assert();
+}
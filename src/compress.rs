@@ -0,0 +1,182 @@
+//! Compression of file blocks.
+//!
+//! The sender offers the algorithms it wants to use in ACK_REQ, the receiver picks the first one
+//! it supports in ACK_RES (`None` if it supports none of them). Every block gets compressed on its
+//! own, blocks that don't get smaller are send raw. Hashes always cover the uncompressed data.
+
+use std::io::{self, ErrorKind};
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Compression {
+    None,
+    Lz4,
+    Zstd,
+}
+
+/// supported algorithms
+pub const SUPPORTED: [Compression; 2] = [Compression::Zstd, Compression::Lz4];
+
+/// zstd level, fast enough for gigabit links
+const ZSTD_LEVEL: i32 = 1;
+
+/// compressed blocks have to be at most this part of the original, otherwise they get send raw
+const MAX_RATIO: f64 = 0.9;
+
+/// file types that are compressed already
+const COMPRESSED_EXTENSIONS: [&str; 32] = [
+    "7z", "avi", "br", "bz2", "deb", "docx", "flac", "gif", "gz", "heic", "jar", "jpeg", "jpg",
+    "lz4", "mkv", "mov", "mp3", "mp4", "ogg", "png", "pptx", "rar", "rpm", "tbz2", "tgz", "txz",
+    "webm", "webp", "xlsx", "xz", "zip", "zst",
+];
+
+impl Compression {
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+            Compression::Zstd => 2,
+        }
+    }
+
+    /// `None` for algorithms of newer peers we don't know
+    pub fn from_byte(b: u8) -> Option<Compression> {
+        match b {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Lz4),
+            2 => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    /// picks the first offered algorithm we support
+    pub fn negotiate(offered: &[Compression]) -> Compression {
+        offered
+            .iter()
+            .copied()
+            .find(|c| SUPPORTED.contains(c))
+            .unwrap_or(Compression::None)
+    }
+
+    /// the algorithm for the file `name`, `zip` etc. don't get smaller and only cost time
+    pub fn for_file(self, name: &str) -> Compression {
+        match name.rsplit_once('.') {
+            Some((_, ext)) if COMPRESSED_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()) => {
+                Compression::None
+            }
+            _ => self,
+        }
+    }
+}
+
+/// compresses the blocks of one file.
+/// if the first block (the sample) doesn't get smaller, the rest of the file is send raw
+pub struct Compressor {
+    algo: Compression,
+    zstd: Option<zstd::bulk::Compressor<'static>>,
+    out: Vec<u8>,
+    sampled: bool,
+}
+
+impl Compressor {
+    pub fn new(algo: Compression) -> io::Result<Compressor> {
+        let zstd = match algo {
+            Compression::Zstd => Some(zstd::bulk::Compressor::new(ZSTD_LEVEL)?),
+            _ => None,
+        };
+
+        Ok(Compressor {
+            algo,
+            zstd,
+            out: Vec::new(),
+            sampled: false,
+        })
+    }
+
+    /// the compressed block, `None` if `data` should be send raw
+    pub fn compress(&mut self, data: &[u8]) -> io::Result<Option<&[u8]>> {
+        self.out.clear();
+        match (self.algo, &mut self.zstd) {
+            (Compression::Zstd, Some(zstd)) => {
+                self.out.reserve(zstd::zstd_safe::compress_bound(data.len()));
+                zstd.compress_to_buffer(data, &mut self.out)?;
+            }
+            (Compression::Lz4, _) => {
+                self.out.resize(lz4_flex::block::get_maximum_output_size(data.len()), 0);
+                let len = lz4_flex::block::compress_into(data, &mut self.out)
+                    .map_err(|e| io::Error::other(e.to_string()))?;
+                self.out.truncate(len);
+            }
+            _ => return Ok(None),
+        }
+
+        let worth_it = (self.out.len() as f64) < data.len() as f64 * MAX_RATIO;
+        if !self.sampled {
+            self.sampled = true;
+            if !worth_it {
+                self.algo = Compression::None;
+            }
+        }
+
+        Ok(if worth_it { Some(&self.out) } else { None })
+    }
+}
+
+/// unpacks a block that was `size` bytes before compression
+pub fn decompress(algo: Compression, data: &[u8], size: usize) -> io::Result<Vec<u8>> {
+    let invalid = |e: String| io::Error::new(ErrorKind::InvalidData, e);
+
+    let out = match algo {
+        Compression::Zstd => {
+            // fails if the data unpacks to more than `size` bytes
+            let mut out = Vec::with_capacity(size);
+            zstd::bulk::Decompressor::new()?
+                .decompress_to_buffer(data, &mut out)
+                .map_err(|e| invalid(e.to_string()))?;
+            out
+        }
+        Compression::Lz4 => {
+            let mut out = vec![0u8; size];
+            let len = lz4_flex::block::decompress_into(data, &mut out).map_err(|e| invalid(e.to_string()))?;
+            out.truncate(len);
+            out
+        }
+        Compression::None => return Err(invalid(String::from("compressed block without compression"))),
+    };
+
+    if out.len() != size {
+        return Err(invalid(String::from("compressed block has the wrong size")));
+    }
+    Ok(out)
+}
+
+#[test]
+fn test_compress_roundtrip() -> io::Result<()> {
+    let text = "2026-10-18 INFO request served in 3ms\n".repeat(1000);
+    let mut x: u64 = 0x9e37_79b9_7f4a_7c15;
+    let noise: Vec<u8> = (0..20_000)
+        .map(|_| {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x as u8
+        })
+        .collect();
+
+    for algo in SUPPORTED.iter().copied() {
+        let mut c = Compressor::new(algo.for_file("server.log"))?;
+        let packed = c.compress(text.as_bytes())?.expect("text compresses").to_vec();
+        assert!(packed.len() < text.len() / 10);
+        assert_eq!(decompress(algo, &packed, text.len())?, text.as_bytes());
+        // a wrong size is an error, not a larger buffer
+        assert!(decompress(algo, &packed, text.len() - 1).is_err());
+
+        let mut c = Compressor::new(algo.for_file("noise.bin"))?;
+        assert!(c.compress(&noise)?.is_none());
+        // after a bad sample the file is send raw
+        assert!(c.compress(text.as_bytes())?.is_none());
+
+        let mut c = Compressor::new(algo.for_file("archive.tar.GZ"))?;
+        assert!(c.compress(text.as_bytes())?.is_none());
+    }
+    Ok(())
+}
@@ -0,0 +1,522 @@
+//! What the protocol runs over. Anything carrying bytes in order in both directions works,
+//! tcp, unix domain sockets, stdin/stdout and the pipes of a command are built in.
+//!
+//! A [`Dial`] opens connections to a receiver (the sender opens extra ones for parallel streams),
+//! a [`Listen`] accepts them on the receiver. Every [`Connection`] gets split into a reading
+//! and a writing half, they are used independently.
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// the other side of a connection
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Peer {
+    Tcp(SocketAddr),
+    /// clients of a unix domain socket have no address, their connections get numbered
+    Unix(u64),
+    /// stdin/stdout or the pipes of a command, named for messages
+    Pipe(String),
+}
+
+impl Peer {
+    /// `None` for connections from this machine
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Peer::Tcp(addr) => Some(addr.ip()),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Peer::Tcp(addr) => write!(f, "{}", addr),
+            Peer::Unix(n) => write!(f, "unix#{}", n),
+            Peer::Pipe(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// where a receiver listens and senders connect to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Endpoint {
+    Tcp(SocketAddr),
+    /// path of a unix domain socket
+    Unix(PathBuf),
+    /// stdin and stdout of this process, they carry a single connection
+    Stdio,
+    /// a shell command whose stdin/stdout carry the connection, e.g. `ssh host sfshare recv --stdio`.
+    /// every connection runs it again, only senders can use it
+    Command(String),
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Tcp(addr) => write!(f, "{}", addr),
+            Endpoint::Unix(path) => write!(f, "unix:{}", path.display()),
+            Endpoint::Stdio => write!(f, "stdio"),
+            Endpoint::Command(command) => write!(f, "{:?}", command),
+        }
+    }
+}
+
+impl From<SocketAddr> for Endpoint {
+    fn from(addr: SocketAddr) -> Endpoint {
+        Endpoint::Tcp(addr)
+    }
+}
+
+/// reading half of a connection
+pub trait ConnRead: Read + Send {
+    /// limits how long a read waits, `None` waits forever. carriers without timeouts ignore it
+    fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// writing half of a connection
+pub trait ConnWrite: Write + Send {
+    /// writes `len` bytes of `file` from `offset` on without copying them through this process.
+    /// `None` if the carrier can't do that, the caller writes the bytes itself then
+    fn send_file(&mut self, _file: &File, _offset: u64, _len: usize) -> Option<io::Result<()>> {
+        None
+    }
+}
+
+impl ConnRead for Box<dyn ConnRead> {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        (**self).set_read_timeout(timeout)
+    }
+}
+
+impl ConnWrite for Box<dyn ConnWrite> {
+    fn send_file(&mut self, file: &File, offset: u64, len: usize) -> Option<io::Result<()>> {
+        (**self).send_file(file, offset, len)
+    }
+}
+
+/// one connection between a sender and a receiver
+pub trait Connection: Send {
+    fn peer(&self) -> Peer;
+
+    /// the reading and the writing half
+    fn split(self: Box<Self>) -> io::Result<(Box<dyn ConnRead>, Box<dyn ConnWrite>)>;
+}
+
+/// opens connections to a receiver
+pub trait Dial: Send + Sync {
+    fn dial(&self) -> io::Result<Box<dyn Connection>>;
+}
+
+/// accepts the connections of senders
+pub trait Listen: Send {
+    /// waits for the next connection, `None` if no more can come
+    fn accept(&mut self) -> Option<io::Result<Box<dyn Connection>>>;
+
+    /// where senders reach the listener, with the actual port if it listens on port 0
+    fn endpoint(&self) -> io::Result<Endpoint>;
+}
+
+impl Dial for SocketAddr {
+    fn dial(&self) -> io::Result<Box<dyn Connection>> {
+        let stream = TcpStream::connect(self)?;
+        Ok(Box::new(Tcp { stream, peer: *self }))
+    }
+}
+
+impl Dial for Endpoint {
+    fn dial(&self) -> io::Result<Box<dyn Connection>> {
+        match self {
+            Endpoint::Tcp(addr) => addr.dial(),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => {
+                let stream = std::os::unix::net::UnixStream::connect(path)?;
+                Ok(Box::new(Unix { stream, number: 0 }))
+            }
+            #[cfg(not(unix))]
+            Endpoint::Unix(_) => Err(unix_unsupported()),
+            Endpoint::Stdio => Ok(Box::new(Stdio::take()?)),
+            Endpoint::Command(command) => Ok(Box::new(Child::spawn(command)?)),
+        }
+    }
+}
+
+/// starts listening on `endpoint`
+pub fn listen(endpoint: &Endpoint) -> io::Result<Box<dyn Listen>> {
+    match endpoint {
+        Endpoint::Tcp(addr) => Ok(Box::new(listen_tcp(*addr)?)),
+        #[cfg(unix)]
+        Endpoint::Unix(path) => Ok(Box::new(UnixListener::bind(path.clone())?)),
+        #[cfg(not(unix))]
+        Endpoint::Unix(_) => Err(unix_unsupported()),
+        Endpoint::Stdio => Ok(Box::new(StdioListener(Some(Stdio::take()?)))),
+        Endpoint::Command(_) => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "can't listen on a command, run the receiver with --stdio inside of it",
+        )),
+    }
+}
+
+#[cfg(not(unix))]
+fn unix_unsupported() -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, "unix domain sockets aren't supported on this system")
+}
+
+/// listens on `addr`, the unspecified ipv6 address accepts ipv4 connections too (dual-stack)
+fn listen_tcp(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+
+    if addr.ip() == IpAddr::V6(Ipv6Addr::UNSPECIFIED) {
+        socket.set_only_v6(false)?;
+    }
+    // allow restarting the receiver while old connections are in TIME_WAIT
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+
+    socket.bind(&addr.into())?;
+    socket.listen(128)?;
+    Ok(socket.into())
+}
+
+impl Listen for TcpListener {
+    fn accept(&mut self) -> Option<io::Result<Box<dyn Connection>>> {
+        Some(TcpListener::accept(self).map(|(stream, peer)| Box::new(Tcp { stream, peer }) as Box<dyn Connection>))
+    }
+
+    fn endpoint(&self) -> io::Result<Endpoint> {
+        self.local_addr().map(Endpoint::Tcp)
+    }
+}
+
+struct Tcp {
+    stream: TcpStream,
+    peer: SocketAddr,
+}
+
+impl Connection for Tcp {
+    fn peer(&self) -> Peer {
+        Peer::Tcp(self.peer)
+    }
+
+    fn split(self: Box<Self>) -> io::Result<(Box<dyn ConnRead>, Box<dyn ConnWrite>)> {
+        Ok((Box::new(self.stream.try_clone()?), Box::new(self.stream)))
+    }
+}
+
+impl ConnRead for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+impl ConnWrite for TcpStream {
+    /// linux sends the data straight from the page cache (sendfile)
+    #[cfg(target_os = "linux")]
+    fn send_file(&mut self, file: &File, offset: u64, len: usize) -> Option<io::Result<()>> {
+        use std::os::unix::io::AsRawFd;
+        Some(sendfile(self.as_raw_fd(), file, offset, len))
+    }
+}
+
+#[cfg(unix)]
+pub use self::unix::UnixListener;
+
+#[cfg(unix)]
+use self::unix::Unix;
+
+#[cfg(unix)]
+mod unix {
+    use super::*;
+    use std::os::unix::net::{self, UnixStream};
+
+    /// a unix domain socket, removed again when the listener is dropped
+    pub struct UnixListener {
+        listener: net::UnixListener,
+        path: PathBuf,
+        /// connections so far, they get numbered instead of an address
+        count: u64,
+    }
+
+    impl UnixListener {
+        /// listens at `path`. a socket file left over by a receiver that didn't stop cleanly
+        /// gets replaced, if nothing listens on it anymore
+        pub fn bind(path: PathBuf) -> io::Result<UnixListener> {
+            let listener = match net::UnixListener::bind(&path) {
+                Err(e) if e.kind() == io::ErrorKind::AddrInUse && UnixStream::connect(&path).is_err() => {
+                    std::fs::remove_file(&path)?;
+                    net::UnixListener::bind(&path)?
+                }
+                res => res?,
+            };
+            Ok(UnixListener { listener, path, count: 0 })
+        }
+    }
+
+    impl Drop for UnixListener {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    impl Listen for UnixListener {
+        fn accept(&mut self) -> Option<io::Result<Box<dyn Connection>>> {
+            self.count += 1;
+            let number = self.count;
+            Some(self.listener.accept().map(|(stream, _)| Box::new(Unix { stream, number }) as Box<dyn Connection>))
+        }
+
+        fn endpoint(&self) -> io::Result<Endpoint> {
+            Ok(Endpoint::Unix(self.path.clone()))
+        }
+    }
+
+    pub(super) struct Unix {
+        pub(super) stream: UnixStream,
+        pub(super) number: u64,
+    }
+
+    impl Connection for Unix {
+        fn peer(&self) -> Peer {
+            Peer::Unix(self.number)
+        }
+
+        fn split(self: Box<Self>) -> io::Result<(Box<dyn ConnRead>, Box<dyn ConnWrite>)> {
+            Ok((Box::new(self.stream.try_clone()?), Box::new(self.stream)))
+        }
+    }
+
+    impl ConnRead for UnixStream {
+        fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+            UnixStream::set_read_timeout(self, timeout)
+        }
+    }
+
+    impl ConnWrite for UnixStream {
+        #[cfg(target_os = "linux")]
+        fn send_file(&mut self, file: &File, offset: u64, len: usize) -> Option<io::Result<()>> {
+            use std::os::unix::io::AsRawFd;
+            Some(sendfile(self.as_raw_fd(), file, offset, len))
+        }
+    }
+}
+
+/// writes `len` bytes of `file` from `offset` on to the socket `fd`
+#[cfg(target_os = "linux")]
+fn sendfile(fd: std::os::unix::io::RawFd, file: &File, offset: u64, mut len: usize) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut offset = offset as libc::off_t;
+    while len > 0 {
+        // advances `offset`, the position of `file` stays the same
+        let n = unsafe { libc::sendfile(fd, file.as_raw_fd(), &mut offset, len) };
+        if n < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+        if n == 0 {
+            return Err(io::Error::from(io::ErrorKind::WriteZero));
+        }
+        len -= n as usize;
+    }
+    Ok(())
+}
+
+/// set once stdin/stdout carry a connection, a second one would mix up the bytes of both
+static STDIO_TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// stdin and stdout of this process as a connection
+pub struct Stdio(());
+
+impl Stdio {
+    /// fails if stdin/stdout carry a connection already
+    pub fn take() -> io::Result<Stdio> {
+        if STDIO_TAKEN.swap(true, Ordering::SeqCst) {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "stdin/stdout carry a single connection only",
+            ));
+        }
+        Ok(Stdio(()))
+    }
+}
+
+impl Connection for Stdio {
+    fn peer(&self) -> Peer {
+        Peer::Pipe(String::from("stdio"))
+    }
+
+    fn split(self: Box<Self>) -> io::Result<(Box<dyn ConnRead>, Box<dyn ConnWrite>)> {
+        Ok((Box::new(io::stdin()), Box::new(Flushing(io::stdout()))))
+    }
+}
+
+impl ConnRead for io::Stdin {}
+
+/// flushes after every write. stdout is line buffered, packets would wait for a newline
+struct Flushing<W: Write>(W);
+
+impl<W: Write> Write for Flushing<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.0.write(buf)?;
+        self.0.flush()?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<W: Write + Send> ConnWrite for Flushing<W> {}
+
+/// hands out stdin/stdout once, then it's done
+struct StdioListener(Option<Stdio>);
+
+impl Listen for StdioListener {
+    fn accept(&mut self) -> Option<io::Result<Box<dyn Connection>>> {
+        self.0.take().map(|s| Ok(Box::new(s) as Box<dyn Connection>))
+    }
+
+    fn endpoint(&self) -> io::Result<Endpoint> {
+        Ok(Endpoint::Stdio)
+    }
+}
+
+/// a command the connection runs through
+struct Child {
+    command: String,
+    child: process::Child,
+}
+
+impl Child {
+    fn spawn(command: &str) -> io::Result<Child> {
+        #[cfg(windows)]
+        let mut shell = process::Command::new("cmd");
+        #[cfg(windows)]
+        shell.arg("/C");
+        #[cfg(not(windows))]
+        let mut shell = process::Command::new("sh");
+        #[cfg(not(windows))]
+        shell.arg("-c");
+
+        // stderr stays ours, so the user sees what ssh etc. complain about
+        let child = shell
+            .arg(command)
+            .stdin(process::Stdio::piped())
+            .stdout(process::Stdio::piped())
+            .spawn()?;
+        Ok(Child {
+            command: command.to_string(),
+            child,
+        })
+    }
+}
+
+impl Connection for Child {
+    fn peer(&self) -> Peer {
+        Peer::Pipe(self.command.clone())
+    }
+
+    fn split(mut self: Box<Self>) -> io::Result<(Box<dyn ConnRead>, Box<dyn ConnWrite>)> {
+        let missing = || io::Error::other("the pipes of the command are gone");
+        let stdout = self.child.stdout.take().ok_or_else(missing)?;
+        let stdin = self.child.stdin.take().ok_or_else(missing)?;
+        Ok((
+            Box::new(stdout),
+            Box::new(ChildInput {
+                stdin: Some(stdin),
+                child: self.child,
+            }),
+        ))
+    }
+}
+
+impl ConnRead for process::ChildStdout {}
+
+/// writing half of a command. closing it waits for the command,
+/// so the receiver at the other end is done once the sender returns
+struct ChildInput {
+    stdin: Option<process::ChildStdin>,
+    child: process::Child,
+}
+
+impl Write for ChildInput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.stdin {
+            Some(s) => s.write(buf),
+            None => Err(io::Error::from(io::ErrorKind::BrokenPipe)),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.stdin {
+            Some(s) => s.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl ConnWrite for ChildInput {}
+
+impl Drop for ChildInput {
+    fn drop(&mut self) {
+        // the command sees the end of its input and finishes
+        drop(self.stdin.take());
+        let _ = self.child.wait();
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_unix_socket() -> io::Result<()> {
+    let path = std::env::temp_dir().join(format!("sfshare_conn_test_{}.sock", std::process::id()));
+    let mut listener = listen(&Endpoint::Unix(path.clone()))?;
+    assert_eq!(listener.endpoint()?, Endpoint::Unix(path.clone()));
+
+    let (_, mut writer) = Endpoint::Unix(path.clone()).dial()?.split()?;
+    writer.write_all(b"hello")?;
+    drop(writer);
+
+    let conn = listener.accept().unwrap()?;
+    assert_eq!(conn.peer(), Peer::Unix(1));
+    assert_eq!(conn.peer().ip(), None);
+    let (mut reader, _) = conn.split()?;
+    let mut got = Vec::new();
+    reader.read_to_end(&mut got)?;
+    assert_eq!(got, b"hello");
+
+    // the socket file goes away with the listener
+    drop(listener);
+    assert!(!path.exists());
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn test_command() -> io::Result<()> {
+    // cat sends everything back
+    let conn = Endpoint::Command(String::from("cat")).dial()?;
+    assert_eq!(conn.peer(), Peer::Pipe(String::from("cat")));
+    let (mut reader, mut writer) = conn.split()?;
+    writer.write_all(b"ping")?;
+    let mut got = [0u8; 4];
+    reader.read_exact(&mut got)?;
+    assert_eq!(&got, b"ping");
+    // waits for cat to finish
+    drop(writer);
+    assert_eq!(reader.read(&mut got)?, 0);
+    Ok(())
+}
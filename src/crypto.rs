@@ -0,0 +1,352 @@
+//! Encryption of the connection.
+//!
+//! After HELLO/HELLO_RES both sides send an ephemeral X25519 public key (KEY_EXCHANGE).
+//! The sender commits to its key with a hash first (KEY_COMMIT) and reveals it after the receiver's
+//! key arrived, so a man in the middle can't try keys until both verification codes match.
+//! The shared secret gets expanded with HKDF-SHA256 into one ChaCha20-Poly1305 key per direction
+//! and a short verification code. Both users compare the code to make sure nobody sits in between.
+//! Every following packet is send as encrypted frame `[4 byte length][ciphertext + 16 byte tag]`.
+//! Extra connections of a parallel transfer run their own key exchange and then prove
+//! with STREAM_JOIN that they belong to an existing session.
+
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+
+/// max plaintext bytes per encrypted frame
+pub const MAX_FRAME_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    Sender,
+    Receiver,
+}
+
+/// one direction of the connection, nonces are a counter so every frame is unique
+pub struct Cipher {
+    aead: ChaCha20Poly1305,
+    counter: u64,
+}
+
+impl Cipher {
+    fn new(key: &[u8; 32]) -> Cipher {
+        Cipher {
+            aead: ChaCha20Poly1305::new(Key::from_slice(key)),
+            counter: 0,
+        }
+    }
+
+    fn next_nonce(&mut self) -> io::Result<[u8; 12]> {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&self.counter.to_be_bytes());
+        self.counter = self
+            .counter
+            .checked_add(1)
+            .ok_or_else(|| io::Error::other("nonce counter exhausted"))?;
+        Ok(nonce)
+    }
+
+    pub fn seal(&mut self, plain: &[u8]) -> io::Result<Vec<u8>> {
+        let nonce = self.next_nonce()?;
+        self.aead
+            .encrypt(Nonce::from_slice(&nonce), plain)
+            .map_err(|_| io::Error::other("encryption failed"))
+    }
+
+    pub fn open(&mut self, sealed: &[u8]) -> io::Result<Vec<u8>> {
+        let nonce = self.next_nonce()?;
+        self.aead
+            .decrypt(Nonce::from_slice(&nonce), sealed)
+            .map_err(|_| io::Error::new(ErrorKind::InvalidData, "frame failed authentication"))
+    }
+}
+
+pub struct SessionKeys {
+    pub send: Cipher,
+    pub recv: Cipher,
+    pub session: Session,
+}
+
+/// what's left of the key exchange once the ciphers are in use
+#[derive(Debug, Clone)]
+pub struct Session {
+    /// short authentication string, identical on both sides if no one tampered with the key exchange
+    pub code: String,
+    /// unique for this connection, differs on both sides if someone sits in between
+    pub binding: [u8; 32],
+    /// secret to add further connections to this session, see `join_proof`
+    pub stream_key: [u8; 32],
+}
+
+/// proves that a new connection (identified by its `binding`) belongs to the session of `stream_key`.
+/// an attacker relaying the new connection has a different binding on each side, so the proof fails
+pub fn join_proof(stream_key: &[u8; 32], binding: &[u8; 32]) -> [u8; 32] {
+    let mut proof = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(binding), stream_key)
+        .expand(b"sfshare stream join", &mut proof)
+        .expect("32 bytes are a valid hkdf output length");
+    proof
+}
+
+/// what the sender sends before its public key. the key has to match it later
+pub fn key_commitment(public: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"sfshare key commitment");
+    hasher.update(public);
+    hasher.finalize().into()
+}
+
+pub struct Handshake {
+    secret: EphemeralSecret,
+    public: PublicKey,
+}
+
+impl Default for Handshake {
+    fn default() -> Handshake {
+        Handshake::new()
+    }
+}
+
+impl Handshake {
+    /// new key pair, a handshake is only used for one connection
+    pub fn new() -> Handshake {
+        let secret = EphemeralSecret::random();
+        let public = PublicKey::from(&secret);
+        Handshake { secret, public }
+    }
+
+    pub fn public_key(&self) -> [u8; 32] {
+        self.public.to_bytes()
+    }
+
+    pub fn commitment(&self) -> [u8; 32] {
+        key_commitment(&self.public_key())
+    }
+
+    pub fn finish(self, role: Role, peer: [u8; 32]) -> io::Result<SessionKeys> {
+        let own = self.public.to_bytes();
+        let shared = self.secret.diffie_hellman(&PublicKey::from(peer));
+
+        if !shared.was_contributory() {
+            return Err(io::Error::new(ErrorKind::InvalidData, "invalid public key of peer"));
+        }
+
+        let (sender_pub, receiver_pub) = match role {
+            Role::Sender => (own, peer),
+            Role::Receiver => (peer, own),
+        };
+
+        let mut salt = [0u8; 64];
+        salt[..32].copy_from_slice(&sender_pub);
+        salt[32..].copy_from_slice(&receiver_pub);
+
+        let hk = Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes());
+        let expand = |info: &[u8], out: &mut [u8]| {
+            hk.expand(info, out)
+                .map_err(|_| io::Error::other("key derivation failed"))
+        };
+
+        let mut to_receiver = [0u8; 32];
+        let mut to_sender = [0u8; 32];
+        let mut code = [0u8; 4];
+        let mut binding = [0u8; 32];
+        let mut stream_key = [0u8; 32];
+        expand(b"sfshare send -> recv", &mut to_receiver)?;
+        expand(b"sfshare recv -> send", &mut to_sender)?;
+        expand(b"sfshare verification code", &mut code)?;
+        expand(b"sfshare binding", &mut binding)?;
+        expand(b"sfshare stream key", &mut stream_key)?;
+
+        let code = u32::from_be_bytes(code) % 1_000_000;
+        let (send, recv) = match role {
+            Role::Sender => (to_receiver, to_sender),
+            Role::Receiver => (to_sender, to_receiver),
+        };
+
+        Ok(SessionKeys {
+            send: Cipher::new(&send),
+            recv: Cipher::new(&recv),
+            session: Session {
+                code: format!("{:03} {:03}", code / 1000, code % 1000),
+                binding,
+                stream_key,
+            },
+        })
+    }
+}
+
+/// writes plain bytes until `start_encryption` is called, encrypted frames afterwards
+pub struct SecureWriter<W: Write> {
+    inner: W,
+    cipher: Option<Cipher>,
+}
+
+impl<W: Write> SecureWriter<W> {
+    pub fn new(inner: W) -> SecureWriter<W> {
+        SecureWriter {
+            inner,
+            cipher: None,
+        }
+    }
+
+    pub fn start_encryption(&mut self, cipher: Cipher) {
+        self.cipher = Some(cipher);
+    }
+
+    /// the underlying writer, `None` once the connection is encrypted
+    pub fn plain_mut(&mut self) -> Option<&mut W> {
+        match self.cipher {
+            Some(_) => None,
+            None => Some(&mut self.inner),
+        }
+    }
+}
+
+impl<W: Write> Write for SecureWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let cipher = match &mut self.cipher {
+            Some(c) => c,
+            None => return self.inner.write(buf),
+        };
+
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let n = buf.len().min(MAX_FRAME_SIZE);
+        let sealed = cipher.seal(&buf[..n])?;
+
+        let mut frame = Vec::with_capacity(4 + sealed.len());
+        frame.extend_from_slice(&(sealed.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&sealed);
+        self.inner.write_all(&frame)?;
+
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// reads plain bytes until `start_encryption` is called, decrypts frames afterwards
+pub struct SecureReader<R: Read> {
+    inner: R,
+    /// frames read along with the handshake, they come before `inner`
+    buffered: io::Cursor<Vec<u8>>,
+    cipher: Option<Cipher>,
+    plain: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> SecureReader<R> {
+    pub fn new(inner: R) -> SecureReader<R> {
+        SecureReader {
+            inner,
+            buffered: io::Cursor::new(Vec::new()),
+            cipher: None,
+            plain: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    fn start_encryption(&mut self, cipher: Cipher) {
+        self.cipher = Some(cipher);
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+}
+
+impl<R: Read> Read for SecureReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let cipher = match &mut self.cipher {
+            Some(c) => c,
+            None => return self.inner.read(buf),
+        };
+
+        if self.pos == self.plain.len() {
+            let mut raw = Read::chain(&mut self.buffered, &mut self.inner);
+            let mut len = [0u8; 4];
+            match raw.read_exact(&mut len) {
+                Ok(()) => {}
+                // connection closed between two frames
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(0),
+                Err(e) => return Err(e),
+            }
+
+            let len = u32::from_be_bytes(len) as usize;
+            if !(TAG_SIZE..=MAX_FRAME_SIZE + TAG_SIZE).contains(&len) {
+                return Err(io::Error::new(ErrorKind::InvalidData, "invalid frame length"));
+            }
+
+            let mut sealed = vec![0u8; len];
+            raw.read_exact(&mut sealed)?;
+            self.plain = cipher.open(&sealed)?;
+            self.pos = 0;
+        }
+
+        let n = buf.len().min(self.plain.len() - self.pos);
+        buf[..n].copy_from_slice(&self.plain[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// switches `reader` to encrypted frames. bytes it read past the handshake already
+/// are the first frames, they get decrypted (and authenticated) like the following ones
+pub fn start_decryption<R: Read>(
+    reader: &mut BufReader<SecureReader<R>>,
+    cipher: Cipher,
+) -> io::Result<()> {
+    let buffered = reader.buffer().to_vec();
+    reader.consume(buffered.len());
+    let inner = reader.get_mut();
+    inner.buffered = io::Cursor::new(buffered);
+    inner.start_encryption(cipher);
+    Ok(())
+}
+
+#[test]
+fn test_encrypted_roundtrip() -> io::Result<()> {
+    let sender = Handshake::new();
+    let receiver = Handshake::new();
+    let sender_pub = sender.public_key();
+
+    assert_eq!(sender.commitment(), key_commitment(&sender_pub));
+    assert_ne!(sender.commitment(), key_commitment(&receiver.public_key()));
+
+    let s_keys = sender.finish(Role::Sender, receiver.public_key())?;
+    let r_keys = receiver.finish(Role::Receiver, sender_pub)?;
+    let (s_session, r_session) = (&s_keys.session, &r_keys.session);
+    assert_eq!(s_session.code, r_session.code);
+    assert_eq!(
+        join_proof(&s_session.stream_key, &s_session.binding),
+        join_proof(&r_session.stream_key, &r_session.binding)
+    );
+
+    let mut writer = SecureWriter::new(Vec::new());
+    writer.write_all(b"key")?;
+    writer.start_encryption(s_keys.send);
+    writer.write_all(b"first frame")?;
+    writer.write_all(&vec![7u8; MAX_FRAME_SIZE + 10])?;
+
+    // the frames get buffered along with the plain handshake
+    let mut reader = BufReader::new(SecureReader::new(&writer.inner[..]));
+    let mut key = [0u8; 3];
+    reader.read_exact(&mut key)?;
+    assert_eq!(&key, b"key");
+    start_decryption(&mut reader, r_keys.recv)?;
+    let mut plain = Vec::new();
+    reader.read_to_end(&mut plain)?;
+
+    assert_eq!(&plain[..11], b"first frame");
+    assert_eq!(plain.len(), 11 + MAX_FRAME_SIZE + 10);
+    Ok(())
+}
@@ -0,0 +1,323 @@
+//! Finding receivers on the local network.
+//!
+//! Receivers listen on UDP port 5124. A sender broadcasts DISCOVER (and sends it to localhost),
+//! every receiver answers with ANNOUNCE containing its device name and TCP port.
+//!
+//! ANNOUNCE is larger than DISCOVER and UDP sources can be forged, so receivers only answer
+//! senders in the local network and only a few times per second.
+
+use crate::event::{Event, Handler};
+use crate::transport::flags;
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use std::collections::HashMap;
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+pub const DISCOVERY_PORT: u16 = 5124;
+const MAGIC: &[u8; 4] = b"SFSH";
+
+/// one source gets at most one answer in this time
+const ANSWER_INTERVAL: Duration = Duration::from_millis(100);
+/// answers to all sources together
+const ANSWERS_PER_SECOND: u32 = 50;
+/// the subnets of the interfaces are read again after this time, addresses change
+const SUBNET_REFRESH: Duration = Duration::from_secs(30);
+/// how long the announcer takes to notice it should stop
+const STOP_POLL: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Receiver {
+    pub name: String,
+    pub addr: SocketAddr,
+}
+
+/// name announced if the user didn't specify one
+pub fn default_name() -> String {
+    std::env::var("HOSTNAME")
+        .or_else(|_| std::env::var("COMPUTERNAME"))
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|n| n.trim().to_string())
+        .filter(|n| !n.is_empty())
+        .unwrap_or_else(|| String::from("sfshare"))
+}
+
+fn announce_packet(name: &str, tcp_port: u16) -> Vec<u8> {
+    // structure : [4 byte magic][1 byte flag ANNOUNCE][2 byte tcp port][2 byte name_len][name utf8]
+    let mut end = name.len().min(255);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    let name = &name.as_bytes()[..end];
+
+    let mut res = Vec::with_capacity(9 + name.len());
+    res.extend_from_slice(MAGIC);
+    res.push(flags::ANNOUNCE);
+    res.extend_from_slice(&tcp_port.to_be_bytes());
+    res.extend_from_slice(&(name.len() as u16).to_be_bytes());
+    res.extend_from_slice(name);
+    res
+}
+
+fn parse_announce(data: &[u8], from: SocketAddr) -> Option<Receiver> {
+    if data.len() < 9 || &data[..4] != MAGIC || data[4] != flags::ANNOUNCE {
+        return None;
+    }
+
+    let tcp_port = u16::from_be_bytes([data[5], data[6]]);
+    let name_len = u16::from_be_bytes([data[7], data[8]]) as usize;
+    let name = String::from_utf8(data.get(9..9 + name_len)?.to_vec()).ok()?;
+
+    Some(Receiver {
+        name,
+        addr: SocketAddr::new(from.ip(), tcp_port),
+    })
+}
+
+/// the ipv4 subnets of this machine's interfaces as (address, netmask)
+fn local_subnets() -> Vec<(Ipv4Addr, Ipv4Addr)> {
+    if_addrs::get_if_addrs()
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|i| match i.addr {
+            if_addrs::IfAddr::V4(a) => Some((a.ip, a.netmask)),
+            if_addrs::IfAddr::V6(_) => None,
+        })
+        .collect()
+}
+
+/// decides which discovery requests get answered
+struct Responder {
+    subnets: Vec<(Ipv4Addr, Ipv4Addr)>,
+    subnets_read: Option<Instant>,
+    /// last answer per source, only sources answered in the current second
+    answered: HashMap<IpAddr, Instant>,
+    /// start of the current second and the answers in it
+    window: (Instant, u32),
+}
+
+impl Responder {
+    fn new() -> Responder {
+        Responder {
+            subnets: Vec::new(),
+            subnets_read: None,
+            answered: HashMap::new(),
+            window: (Instant::now(), 0),
+        }
+    }
+
+    /// loopback, private and link-local addresses, or on the subnet of an interface
+    fn is_local(&mut self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V4(ip) => ip,
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => ip,
+                None => return ip.is_loopback(),
+            },
+        };
+        if ip.is_loopback() || ip.is_private() || ip.is_link_local() {
+            return true;
+        }
+
+        if self.subnets_read.is_none_or(|t| t.elapsed() > SUBNET_REFRESH) {
+            self.subnets = local_subnets();
+            self.subnets_read = Some(Instant::now());
+        }
+        let ip = u32::from(ip);
+        self.subnets
+            .iter()
+            .any(|&(net, mask)| ip & u32::from(mask) == u32::from(net) & u32::from(mask))
+    }
+
+    /// if `from` gets an answer now, counts it if so
+    fn answer(&mut self, from: IpAddr, now: Instant) -> bool {
+        if !self.is_local(from) {
+            return false;
+        }
+
+        if now.duration_since(self.window.0) >= Duration::from_secs(1) {
+            self.window = (now, 0);
+            self.answered.retain(|_, t| now.duration_since(*t) < ANSWER_INTERVAL);
+        }
+        if self.window.1 >= ANSWERS_PER_SECOND
+            || self.answered.get(&from).is_some_and(|t| now.duration_since(*t) < ANSWER_INTERVAL)
+        {
+            return false;
+        }
+
+        self.window.1 += 1;
+        self.answered.insert(from, now);
+        true
+    }
+}
+
+/// answers discovery requests on `socket` until `stop` is set or an error occurs
+pub fn announce(socket: UdpSocket, name: &str, tcp_port: u16, stop: &AtomicBool) -> io::Result<()> {
+    let answer = announce_packet(name, tcp_port);
+    let mut responder = Responder::new();
+    let mut buf = [0u8; 64];
+    socket.set_read_timeout(Some(STOP_POLL))?;
+
+    while !stop.load(Ordering::Relaxed) {
+        let (n, from) = match socket.recv_from(&mut buf) {
+            Ok(r) => r,
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => continue,
+            Err(e) => return Err(e),
+        };
+
+        if n == 5 && &buf[..4] == MAGIC && buf[4] == flags::DISCOVER && responder.answer(from.ip(), Instant::now()) {
+            socket.send_to(&answer, from)?;
+        }
+    }
+    Ok(())
+}
+
+/// several receivers on one machine share the discovery port, broadcasts reach all of them
+fn bind_shared(port: u16) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port).into())?;
+    Ok(socket.into())
+}
+
+/// the thread answering discovery requests, it stops when this is dropped
+pub(crate) struct Announcer {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for Announcer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// answers discovery requests in a background thread, so senders can find us by `name`.
+/// failures go to `events`, `None` if it can't listen
+pub(crate) fn spawn_announcer(name: String, tcp_port: u16, events: Handler) -> Option<Announcer> {
+    let socket = match bind_shared(DISCOVERY_PORT) {
+        Ok(s) => s,
+        Err(e) => {
+            events(&Event::Discovery {
+                text: format!("Can't listen for discovery requests, senders need the ip address: {}", e),
+            });
+            return None;
+        }
+    };
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let thread = {
+        let stop = Arc::clone(&stop);
+        std::thread::spawn(move || {
+            if let Err(e) = announce(socket, &name, tcp_port, &stop) {
+                events(&Event::Discovery {
+                    text: format!("Discovery stopped: {}", e),
+                });
+            }
+        })
+    };
+    Some(Announcer {
+        stop,
+        thread: Some(thread),
+    })
+}
+
+/// asks all receivers in the local network (and on this machine) for their name.
+/// targets the request can't be sent to are reported to `events`
+pub fn find_receivers(discovery_port: u16, wait: Duration, events: &dyn Fn(&Event)) -> io::Result<Vec<Receiver>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_broadcast(true)?;
+
+    let mut request = Vec::with_capacity(5);
+    request.extend_from_slice(MAGIC);
+    request.push(flags::DISCOVER);
+
+    let targets = [Ipv4Addr::BROADCAST, Ipv4Addr::LOCALHOST];
+    for ip in targets.iter() {
+        // broadcast fails without network, localhost still works
+        if let Err(e) = socket.send_to(&request, (IpAddr::V4(*ip), discovery_port)) {
+            events(&Event::Discovery {
+                text: format!("Can't send discovery request to {}: {}", ip, e),
+            });
+        }
+    }
+
+    let deadline = Instant::now() + wait;
+    let mut found: Vec<Receiver> = Vec::new();
+    let mut buf = [0u8; 512];
+
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left == Duration::from_secs(0) {
+            break;
+        }
+        socket.set_read_timeout(Some(left))?;
+
+        match socket.recv_from(&mut buf) {
+            Ok((n, from)) => {
+                if let Some(r) = parse_announce(&buf[..n], from) {
+                    if !found.contains(&r) {
+                        found.push(r);
+                    }
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                break
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(found)
+}
+
+#[test]
+fn test_discovery_loopback() -> io::Result<()> {
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+    let port = socket.local_addr()?.port();
+    let stop = Arc::new(AtomicBool::new(false));
+    let announcer = {
+        let stop = Arc::clone(&stop);
+        std::thread::spawn(move || announce(socket, "test-device", 4321, &stop))
+    };
+
+    let found = find_receivers(port, Duration::from_millis(500), &|_| {})?;
+
+    assert!(found.contains(&Receiver {
+        name: String::from("test-device"),
+        addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 4321),
+    }));
+    stop.store(true, Ordering::Relaxed);
+    announcer.join().unwrap()
+}
+
+#[test]
+fn test_responder() {
+    let mut responder = Responder::new();
+    let now = Instant::now();
+    let lan: IpAddr = Ipv4Addr::new(192, 168, 1, 20).into();
+
+    assert!(responder.answer(lan, now));
+    // not again right away
+    assert!(!responder.answer(lan, now + ANSWER_INTERVAL / 2));
+    assert!(responder.answer(lan, now + ANSWER_INTERVAL));
+    // public addresses that aren't on a subnet of this machine
+    assert!(!responder.answer(Ipv4Addr::new(203, 0, 113, 7).into(), now));
+    assert!(responder.answer(Ipv4Addr::LOCALHOST.into(), now));
+
+    // many sources together hit the limit of the second
+    let answered = (0..=255u8)
+        .filter(|&i| responder.answer(Ipv4Addr::new(10, 0, 0, i).into(), now + ANSWER_INTERVAL))
+        .count();
+    assert_eq!(answered as u32, ANSWERS_PER_SECOND - 3);
+    assert!(responder.answer(Ipv4Addr::new(10, 0, 1, 1).into(), now + Duration::from_secs(2)));
+}
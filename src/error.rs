@@ -0,0 +1,108 @@
+//! Errors of sending and receiving, every kind ends the program with its own exit code.
+//!
+//! Low level code works with `io::Error`, `?` sorts these by their kind: `InvalidData` is a
+//! protocol violation of the peer, connection problems are network errors, the rest is local I/O.
+
+use std::fmt;
+use std::io::{self, ErrorKind};
+
+#[derive(Debug)]
+pub enum Error {
+    /// bad arguments, unknown receiver, no files found
+    Usage(String),
+    /// local file system errors
+    Io(io::Error),
+    /// the peer can't be reached or the connection broke
+    Network(io::Error),
+    /// the peer sent something that doesn't follow the protocol
+    Protocol(String),
+    /// the protocol versions of both sides don't fit
+    Incompatible(String),
+    /// the user or the receiver declined
+    Rejected(String),
+    /// names of the files whose hash didn't match
+    Checksum(Vec<String>),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Io(_) => 1,
+            // like clap for invalid arguments
+            Error::Usage(_) => 2,
+            Error::Network(_) => 3,
+            Error::Protocol(_) => 4,
+            Error::Incompatible(_) => 5,
+            Error::Rejected(_) => 6,
+            Error::Checksum(_) => 7,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Usage(text) | Error::Incompatible(text) | Error::Rejected(text) => f.write_str(text),
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Network(e) => write!(f, "connection failed: {}", e),
+            Error::Protocol(text) => write!(f, "protocol violation: {}", text),
+            Error::Checksum(files) => write!(f, "hash not identical for {}", files.join(", ")),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// `io::Error` can't be cloned, the copy keeps its kind and message
+impl Clone for Error {
+    fn clone(&self) -> Error {
+        let copy = |e: &io::Error| io::Error::new(e.kind(), e.to_string());
+        match self {
+            Error::Usage(text) => Error::Usage(text.clone()),
+            Error::Io(e) => Error::Io(copy(e)),
+            Error::Network(e) => Error::Network(copy(e)),
+            Error::Protocol(text) => Error::Protocol(text.clone()),
+            Error::Incompatible(text) => Error::Incompatible(text.clone()),
+            Error::Rejected(text) => Error::Rejected(text.clone()),
+            Error::Checksum(files) => Error::Checksum(files.clone()),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        match e.kind() {
+            ErrorKind::InvalidData => Error::Protocol(e.to_string()),
+            ErrorKind::UnexpectedEof
+            | ErrorKind::ConnectionRefused
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::NotConnected
+            | ErrorKind::BrokenPipe
+            | ErrorKind::TimedOut
+            | ErrorKind::AddrInUse
+            | ErrorKind::AddrNotAvailable
+            | ErrorKind::HostUnreachable
+            | ErrorKind::NetworkUnreachable => Error::Network(e),
+            _ => Error::Io(e),
+        }
+    }
+}
+
+#[test]
+fn test_error_kinds() {
+    let kinds: Vec<Error> = vec![
+        io::Error::from(ErrorKind::NotFound).into(),
+        Error::Usage(String::new()),
+        io::Error::from(ErrorKind::ConnectionReset).into(),
+        io::Error::new(ErrorKind::InvalidData, "unknown packet").into(),
+        Error::Incompatible(String::new()),
+        Error::Rejected(String::new()),
+        Error::Checksum(vec![String::from("a")]),
+    ];
+    // every kind has its own code
+    let codes: Vec<i32> = kinds.iter().map(Error::exit_code).collect();
+    assert_eq!(codes, (1..=7).collect::<Vec<i32>>());
+}
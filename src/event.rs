@@ -0,0 +1,72 @@
+//! What senders and receivers report while they work, see `Sender::on_event` and `Receiver::on_event`.
+//!
+//! The handlers get called from the threads doing the work, a receiver calls them from every
+//! connection at once. They shouldn't block for long, the transfer waits for them.
+
+use crate::conn::{Endpoint, Peer};
+use crate::error::Error;
+use crate::transport::FileMeta;
+
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// a transmission request
+#[derive(Debug, Clone)]
+pub struct Request {
+    /// the other side, the sender on the receiver and the other way around
+    pub peer: Peer,
+    /// files, directories and symlinks of the request
+    pub files: Vec<FileMeta>,
+    /// verification code of the encrypted connection, both sides show the same one.
+    /// `None` if the connection isn't encrypted
+    pub code: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// receiver: listens on `addr` now
+    Listening { addr: Endpoint },
+    /// a new connection, the sender reports it after both sides agreed on a protocol version
+    Connected { peer: Peer },
+    /// receiver: a request arrived, it gets answered next.
+    /// sender: the request was sent, the receiver answers it next
+    Request(Request),
+    /// `accepted` of the `total` files get transmitted
+    Accepted { peer: Peer, accepted: usize, total: usize },
+    /// the request was declined, the `reason` may be empty
+    Rejected { peer: Peer, reason: String },
+    /// `bytes` of the `total` bytes of the request are transmitted, `file` is `(k, n)` of the current one
+    Progress {
+        peer: Peer,
+        name: String,
+        file: (usize, usize),
+        bytes: u64,
+        total: u64,
+    },
+    /// a file is transmitted. `path` is where the receiver stored it, `None` on the sender
+    FileDone { peer: Peer, name: String, path: Option<PathBuf> },
+    /// something worth telling the user
+    Message { peer: Peer, text: String },
+    /// something went wrong, the transfer goes on anyway
+    Warning { peer: Peer, text: String },
+    /// receiver: a connection failed, the receiver goes on with the others.
+    /// `peer` is `None` if the connection couldn't be accepted at all
+    Error { peer: Option<Peer>, error: Error },
+    /// receiver: the connection is done
+    Closed { peer: Peer },
+    /// finding receivers by name or announcing this one doesn't work (fully),
+    /// the address of the receiver still does
+    Discovery { text: String },
+}
+
+pub(crate) type Handler = Arc<dyn Fn(&Event) + Send + Sync>;
+
+/// decides about a request the policy doesn't answer, returns the ids of the accepted files.
+/// `None` or no file rejects the request
+pub(crate) type Ask = Arc<dyn Fn(&Request) -> Option<HashSet<u32>> + Send + Sync>;
+
+/// handler of builders nobody set one for
+pub(crate) fn ignore() -> Handler {
+    Arc::new(|_| {})
+}
@@ -0,0 +1,121 @@
+//! Content hashes of transmitted files.
+//!
+//! The sender offers all algorithms it supports in ACK_REQ, the receiver picks one in ACK_RES.
+//! `Additive` is the byte sum of the first protocol version, every peer supports it.
+
+use sha2::Digest;
+use std::io;
+
+pub const CHECKSUM_MOD: u64 = 2147483647;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HashAlgo {
+    Additive,
+    Sha256,
+    Blake3,
+}
+
+/// supported algorithms, most preferred first
+pub const SUPPORTED: [HashAlgo; 3] = [HashAlgo::Blake3, HashAlgo::Sha256, HashAlgo::Additive];
+
+impl HashAlgo {
+    pub fn to_byte(self) -> u8 {
+        match self {
+            HashAlgo::Additive => 0,
+            HashAlgo::Sha256 => 1,
+            HashAlgo::Blake3 => 2,
+        }
+    }
+
+    /// `None` for algorithms of newer peers we don't know
+    pub fn from_byte(b: u8) -> Option<HashAlgo> {
+        match b {
+            0 => Some(HashAlgo::Additive),
+            1 => Some(HashAlgo::Sha256),
+            2 => Some(HashAlgo::Blake3),
+            _ => None,
+        }
+    }
+
+    /// picks our most preferred algorithm the peer offered
+    pub fn negotiate(offered: &[HashAlgo]) -> HashAlgo {
+        SUPPORTED
+            .iter()
+            .copied()
+            .find(|a| offered.contains(a))
+            .unwrap_or(HashAlgo::Additive)
+    }
+}
+
+#[derive(Clone)]
+pub enum Hasher {
+    Additive(u64),
+    Sha256(sha2::Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    pub fn new(algo: HashAlgo) -> Hasher {
+        match algo {
+            HashAlgo::Additive => Hasher::Additive(0),
+            HashAlgo::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+            HashAlgo::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Additive(cs) => {
+                *cs = (*cs + data.iter().fold(0u64, |acc, b| acc + *b as u64)) % CHECKSUM_MOD
+            }
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Blake3(h) => {
+                h.update(data);
+            }
+        }
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        match self {
+            Hasher::Additive(cs) => cs.to_be_bytes().to_vec(),
+            Hasher::Sha256(h) => h.finalize().to_vec(),
+            Hasher::Blake3(h) => h.finalize().as_bytes().to_vec(),
+        }
+    }
+
+    /// hashes the next `len` bytes of `reader`
+    pub fn update_from<R: io::Read>(&mut self, reader: &mut R, mut len: u64) -> io::Result<()> {
+        let mut buf = vec![0u8; 64 * 1024];
+        while len > 0 {
+            let n = (buf.len() as u64).min(len) as usize;
+            reader.read_exact(&mut buf[..n])?;
+            self.update(&buf[..n]);
+            len -= n as u64;
+        }
+        Ok(())
+    }
+}
+
+/// hex representation for messages
+pub fn to_hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[test]
+fn test_hash_detects_reordering() {
+    for algo in SUPPORTED.iter().copied() {
+        let mut a = Hasher::new(algo);
+        a.update(b"block 1");
+        a.update(b"block 2");
+
+        let mut b = Hasher::new(algo);
+        b.update(b"block 2");
+        b.update(b"block 1");
+
+        if algo == HashAlgo::Additive {
+            assert_eq!(a.finish(), b.finish());
+        } else {
+            assert_ne!(a.finish(), b.finish());
+        }
+    }
+}
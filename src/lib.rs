@@ -0,0 +1,44 @@
+//! Simple file sharing in the local network, as a library.
+//!
+//! A [`Sender`] sends the files of a [`Manifest`] to a [`Receiver`], which stores the accepted ones
+//! in its download directory. Connections are encrypted and every file is checked by its hash.
+//! They run over tcp, unix domain sockets, stdin/stdout or anything else implementing the
+//! traits of [`conn`].
+//! Both sides report what they do as [`Event`]s:
+//!
+//! ```no_run
+//! use sfshare::{Event, Manifest, Sender};
+//! use std::net::SocketAddr;
+//!
+//! let mut manifest = Manifest::new();
+//! manifest.add("notes.txt")?;
+//! Sender::new("192.168.1.20:5123".parse::<SocketAddr>().unwrap())
+//!     .on_event(|e| {
+//!         if let Event::Progress { bytes, total, .. } = e {
+//!             println!("{} of {} bytes", bytes, total);
+//!         }
+//!     })
+//!     .send(&manifest)?;
+//! # Ok::<(), sfshare::Error>(())
+//! ```
+
+pub mod compress;
+pub mod conn;
+pub mod crypto;
+pub mod discovery;
+pub mod error;
+pub mod event;
+pub mod hash;
+pub mod manifest;
+pub mod paths;
+pub mod policy;
+pub mod recv;
+pub mod send;
+pub mod transport;
+
+pub use conn::{Endpoint, Peer};
+pub use error::{Error, Result};
+pub use event::{Event, Request};
+pub use manifest::Manifest;
+pub use recv::Receiver;
+pub use send::Sender;
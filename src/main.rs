@@ -1,58 +1,189 @@
-use std::env;
-use std::fs::File;
-use std::io::{self, stdout, Write};
+use std::fs::{File, OpenOptions};
+use std::io::{self, stdout, IsTerminal, Write};
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
 
+use clap::Parser;
 use crossterm::{cursor, queue};
-use crossterm::style::{self, Colorize};
+use crossterm::style::{self, Stylize};
 
-use utils::s_contains;
+use cli::{Cli, Command};
+use progress::Progress;
+use sfshare::{compress, discovery, paths, policy, Endpoint, Error, Manifest, Receiver, Sender};
 
-mod recv;
-mod send;
-mod transport;
-mod utils;
+mod bench;
+mod cli;
+mod progress;
+mod term;
 
 pub enum AppState {
     Send {
-        to: std::net::SocketAddr,
+        to: Endpoint,
         files: Vec<PathBuf>,
+        /// parallel connections for large files, 1 sends everything over the main connection
+        streams: usize,
+        /// unencrypted connections allow sending straight from the page cache
+        encrypt: bool,
+        compression: compress::Compression,
     },
-    Recv,
+    Recv {
+        name: String,
+        listen: Endpoint,
+        policy: policy::AcceptPolicy,
+        dir: paths::DownloadDir,
+    },
+    List,
     GenTestData(PathBuf, u64),
+    Bench(u64),
 }
 
-fn main() -> std::io::Result<()> {
-    let args: Vec<String> = env::args().collect();
-
-    let bin_name = &args[0];
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("Error: {}", e);
+        std::process::exit(e.exit_code());
+    }
+}
 
-    let state = if s_contains(&args, "send") {
-        send::match_send(&args)?
-    } else if s_contains(&args, "recv") {
-        AppState::Recv
-    } else if s_contains(&args, "testgen") {
-        if args.len() != 4 {
-            return Err(std::io::Error::from(std::io::ErrorKind::InvalidInput));
+fn run() -> sfshare::Result<()> {
+    let cli = Cli::parse();
+
+    let state = match cli.command {
+        Command::Send {
+            to,
+            files,
+            via,
+            streams,
+            no_encryption,
+            compress,
+        } => cli::match_send(to, via, &files, streams as usize, !no_encryption, compress)?,
+        Command::Recv {
+            name,
+            bind,
+            port,
+            unix,
+            stdio,
+            yes,
+            allow,
+            max_size,
+            max_files,
+            allow_unencrypted,
+            on_conflict,
+            out_dir,
+            discard_partial,
+        } => AppState::Recv {
+            name: name.unwrap_or_else(discovery::default_name),
+            listen: match (unix, stdio) {
+                (Some(path), _) => Endpoint::Unix(path),
+                (None, true) => Endpoint::Stdio,
+                (None, false) => Endpoint::Tcp(std::net::SocketAddr::new(bind, port)),
+            },
+            policy: policy::AcceptPolicy {
+                yes,
+                allow,
+                max_size: max_size
+                    .map(|mb| {
+                        mb.checked_mul(1_000_000)
+                            .ok_or_else(|| Error::Usage(format!("--max-size {} mb is too large", mb)))
+                    })
+                    .transpose()?,
+                max_files,
+                allow_unencrypted,
+                conflict: on_conflict,
+            },
+            dir: paths::DownloadDir::new(&out_dir, !discard_partial)?,
+        },
+        Command::List => AppState::List,
+        Command::Testgen { file, size } => AppState::GenTestData(file, size),
+        Command::Bench { size } => AppState::Bench(size),
+        Command::Completions { shell } => {
+            cli::print_completions(shell);
+            return Ok(());
         }
-
-        let fname = PathBuf::from(&args[2]);
-        let size: u64 = args[3].parse().expect("Size in integer mb");
-
-        AppState::GenTestData(fname, size)
-    } else {
-        println!("No mode specified!\nUsage:\n\t{binname} recv\t\t| waits for files\n\t{binname} send [addr ipv6 / ipv4] [list of files]", binname = bin_name);
-        return Err(std::io::Error::from(std::io::ErrorKind::InvalidInput));
     };
 
-    print_info(&state).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    // stdout carries the connection then
+    if !matches!(state, AppState::Recv { listen: Endpoint::Stdio, .. }) {
+        print_info(&state)?;
+    }
 
     match state {
-        AppState::Send { .. } => {
-            send::send(state)?;
+        AppState::Send {
+            to,
+            files,
+            streams,
+            encrypt,
+            compression,
+        } => {
+            let mut manifest = Manifest::new();
+            for path in files {
+                if let Err(e) = manifest.add(&path) {
+                    eprintln!("Skipping {:?}: {}", path, e);
+                }
+            }
+            for (path, reason) in manifest.skipped() {
+                eprintln!("Skipping {:?}: {}", path, reason);
+            }
+            if manifest.is_empty() {
+                return Err(Error::Usage(String::from("No files found")));
+            }
+            if !term::confirm_send(&manifest)? {
+                return Err(Error::Rejected(String::from("Sending cancelled")));
+            }
+
+            let start = Instant::now();
+            let tcp = matches!(to, Endpoint::Tcp(_));
+            let sent = Sender::new(to)
+                .streams(streams)
+                .encrypt(encrypt)
+                .compression(compression)
+                .on_event(term::send_events())
+                .send(&manifest);
+            if let (Err(Error::Network(e)), true) = (&sent, tcp) {
+                if e.kind() == io::ErrorKind::ConnectionRefused || e.kind() == io::ErrorKind::TimedOut {
+                    eprintln!("Receiver can't be reached. Make sure that both are connected to the same network and sfshare is running in recv mode.");
+                }
+            }
+            sent?;
+            println!("Took {}s", start.elapsed().as_secs_f64());
+        }
+        AppState::Recv {
+            name,
+            listen,
+            policy,
+            dir,
+        } => {
+            let receiver = Receiver::new(dir).endpoint(listen.clone()).announce(name).policy(policy);
+            if listen == Endpoint::Stdio {
+                // stdin is taken, questions go to the terminal if there is one (not through ssh).
+                // without one requests need --yes
+                let progress = Arc::new(Progress::on_stderr());
+                let receiver = receiver.on_event(term::recv_events(Arc::clone(&progress)));
+                let tty = OpenOptions::new().read(true).write(true).open("/dev/tty");
+                match tty {
+                    Ok(tty) if io::stderr().is_terminal() => receiver.on_ask(term::ask_tty(progress, tty)).run()?,
+                    _ => receiver.run()?,
+                }
+            } else {
+                let progress = Arc::new(Progress::default());
+                receiver
+                    .on_event(term::recv_events(Arc::clone(&progress)))
+                    .on_ask(term::ask(progress))
+                    .run()?;
+            }
         }
-        AppState::Recv => {
-            recv::recv()?;
+        AppState::List => {
+            let receivers = discovery::find_receivers(
+                discovery::DISCOVERY_PORT,
+                std::time::Duration::from_secs(1),
+                &term::send_events(),
+            )?;
+            if receivers.is_empty() {
+                println!("No receivers found");
+            }
+            for r in receivers {
+                println!("{:30} | {}", r.name, r.addr);
+            }
         }
         AppState::GenTestData(fname, size) => {
             let mut f = File::create(&fname)?;
@@ -67,12 +198,13 @@ fn main() -> std::io::Result<()> {
                 println!("Written {}/{} mb", mb + 1, size);
             }
         }
+        AppState::Bench(size) => bench::run(size)?,
     }
 
     Ok(())
 }
 
-fn print_info(state: &AppState) -> crossterm::Result<()> {
+fn print_info(state: &AppState) -> io::Result<()> {
     let mut stdout = stdout();
     queue!(
         stdout,
@@ -81,11 +213,19 @@ fn print_info(state: &AppState) -> crossterm::Result<()> {
     )?;
 
     match state {
-        AppState::Send { to, files } => println!("Sending {:?} to {}", files, to),
-        &AppState::Recv => println!("Waiting for files to receive"),
-        &AppState::GenTestData(ref fname, ref size) => {
+        AppState::Send { to, files, .. } => println!("Sending {:?} to {}", files, to),
+        AppState::Recv {
+            name,
+            dir,
+            listen: Endpoint::Tcp(_),
+            ..
+        } => println!("Waiting for files to receive as {} (saving to {:?})", name, dir.root()),
+        AppState::Recv { dir, .. } => println!("Waiting for files (saving to {:?})", dir.root()),
+        AppState::List => println!("Searching for receivers..."),
+        AppState::GenTestData(fname, size) => {
             println!("Generating {:?} with {} mb", fname, size)
         }
+        AppState::Bench(size) => println!("Sending {} mb over loopback", size),
     }
 
     stdout.flush()?;
@@ -0,0 +1,126 @@
+//! The entries of a transmission request, built from local paths.
+
+use crate::transport::{self, EntryKind, FileMeta};
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// files, directories and symlinks to send, with the names the receiver stores them under
+#[derive(Debug, Clone, Default)]
+pub struct Manifest {
+    entries: Vec<FileMeta>,
+    /// entries inside of added directories that couldn't be read, with the reason
+    skipped: Vec<(PathBuf, String)>,
+}
+
+impl Manifest {
+    pub fn new() -> Manifest {
+        Manifest::default()
+    }
+
+    /// adds the file or directory at `path` under its file name, directories with all their contents.
+    /// symlinks inside directories get send as links, they aren't followed
+    pub fn add<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let fm = FileMeta::from(path.to_path_buf())?;
+        let is_dir = fm.kind == EntryKind::Dir;
+        let name = fm.name.clone();
+        self.entries.push(fm);
+
+        if is_dir {
+            if let Err(e) = self.walk_dir(path, &name) {
+                self.skipped.push((path.to_path_buf(), e.to_string()));
+            }
+        }
+
+        transport::assign_ids(&mut self.entries);
+        Ok(())
+    }
+
+    /// adds the entries of `dir` and all its subdirectories, named relative to the transmitted root
+    fn walk_dir(&mut self, dir: &Path, rel_name: &str) -> io::Result<()> {
+        let mut entries = std::fs::read_dir(dir)?
+            .map(|e| e.map(|e| e.path()))
+            .collect::<io::Result<Vec<PathBuf>>>()?;
+        entries.sort();
+
+        for path in entries {
+            let name = match path.file_name().and_then(|n| n.to_str()) {
+                Some(n) => format!("{}/{}", rel_name, n),
+                None => {
+                    self.skipped.push((path, String::from("name is not valid utf8")));
+                    continue;
+                }
+            };
+
+            let fm = FileMeta::with_name(path.clone(), name.clone(), false)?;
+            let is_dir = fm.kind == EntryKind::Dir;
+            self.entries.push(fm);
+
+            if is_dir {
+                self.walk_dir(&path, &name)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn entries(&self) -> &[FileMeta] {
+        &self.entries
+    }
+
+    /// what `add` had to leave out
+    pub fn skipped(&self) -> &[(PathBuf, String)] {
+        &self.skipped
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// number of files, without directories and symlinks
+    pub fn file_count(&self) -> usize {
+        self.entries.iter().filter(|e| e.kind == EntryKind::File).count()
+    }
+
+    /// size of all files in bytes
+    pub fn total_size(&self) -> u64 {
+        self.entries.iter().map(|e| e.size).sum()
+    }
+}
+
+impl From<Vec<FileMeta>> for Manifest {
+    /// numbers the entries again, ids have to be unique
+    fn from(mut entries: Vec<FileMeta>) -> Manifest {
+        transport::assign_ids(&mut entries);
+        Manifest {
+            entries,
+            skipped: Vec::new(),
+        }
+    }
+}
+
+#[test]
+fn test_manifest() -> io::Result<()> {
+    let root = std::env::temp_dir().join("sfshare_manifest_test");
+    let _ = std::fs::remove_dir_all(&root);
+    std::fs::create_dir_all(root.join("dir/sub"))?;
+    std::fs::write(root.join("dir/a.txt"), b"12345")?;
+    std::fs::write(root.join("dir/sub/b.txt"), b"123")?;
+    std::fs::write(root.join("c.txt"), b"1")?;
+
+    let mut manifest = Manifest::new();
+    manifest.add(root.join("dir"))?;
+    manifest.add(root.join("c.txt"))?;
+    assert!(manifest.add(root.join("missing")).is_err());
+
+    let names: Vec<&str> = manifest.entries().iter().map(|e| e.name.as_str()).collect();
+    assert_eq!(names, ["dir", "dir/a.txt", "dir/sub", "dir/sub/b.txt", "c.txt"]);
+    let ids: Vec<u32> = manifest.entries().iter().map(|e| e.id).collect();
+    assert_eq!(ids, [0, 1, 2, 3, 4]);
+    assert_eq!(manifest.file_count(), 3);
+    assert_eq!(manifest.total_size(), 9);
+    assert!(manifest.skipped().is_empty());
+
+    std::fs::remove_dir_all(&root)
+}
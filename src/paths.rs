@@ -0,0 +1,263 @@
+//! Where received entries end up on disk.
+//!
+//! Names come straight off the wire, so they get checked before anything touches the disk:
+//! only plain relative paths below the download directory are allowed, and symlinks inside
+//! the download directory can't be used to leave it either.
+//!
+//! Files get written to `<path>.part` first and only get their real name once the hash matched.
+
+use crate::transport::FileMeta;
+
+use std::collections::HashSet;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+
+/// what happens if a received file exists already
+#[derive(Debug, Clone, Copy, PartialEq, Default, clap::ValueEnum)]
+pub enum Conflict {
+    /// replace the existing file
+    Overwrite,
+    /// don't accept the file
+    Skip,
+    /// store it as `name (1).ext`
+    #[default]
+    Rename,
+}
+
+/// names windows reserves for devices, with any extension
+#[cfg(windows)]
+const RESERVED_NAMES: [&str; 22] = [
+    "con", "prn", "aux", "nul", "com1", "com2", "com3", "com4", "com5", "com6", "com7", "com8", "com9", "lpt1",
+    "lpt2", "lpt3", "lpt4", "lpt5", "lpt6", "lpt7", "lpt8", "lpt9",
+];
+
+/// checks a name of ACK_REQ, `Err` holds the reason.
+/// names only windows can't store (`:`, device names like `aux.c`) are fine elsewhere
+pub fn check_name(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err(String::from("empty name"));
+    }
+    if name.starts_with('/') {
+        return Err(String::from("absolute path"));
+    }
+    if name.chars().any(|c| c.is_control()) {
+        return Err(String::from("control characters"));
+    }
+    // the separator of windows, a sender on windows never means it as part of a name
+    if name.contains('\\') {
+        return Err(String::from("`\\`"));
+    }
+    // drive letters and alternate data streams
+    #[cfg(windows)]
+    if name.contains(':') {
+        return Err(String::from("`:`"));
+    }
+
+    for component in name.split('/') {
+        match component {
+            "" => return Err(String::from("empty path component")),
+            "." | ".." => return Err(format!("`{}` in path", component)),
+            _ => {}
+        }
+        #[cfg(windows)]
+        {
+            let base = component.split('.').next().unwrap_or("").trim_end().to_ascii_lowercase();
+            if RESERVED_NAMES.contains(&base.as_str()) {
+                return Err(format!("reserved name `{}`", component));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// checks the target of the symlink `name`, it has to stay inside the download directory
+pub fn check_link(name: &str, target: &str) -> Result<(), String> {
+    let drive = cfg!(windows) && target.contains(':');
+    if target.is_empty() || target.starts_with('/') || target.contains('\\') || drive {
+        return Err(format!("link target {:?} isn't a relative path", target));
+    }
+    if target.chars().any(|c| c.is_control()) {
+        return Err(String::from("control characters in link target"));
+    }
+
+    let ups = link_ups(target)?;
+    if ups >= name.split('/').count() {
+        return Err(format!("link target {:?} leads outside of the download directory", target));
+    }
+    Ok(())
+}
+
+/// number of leading `..` of a link target. `..` after a folder name isn't allowed,
+/// the folder could be a link and `..` would go up from where it points to
+fn link_ups(target: &str) -> Result<usize, String> {
+    let mut ups = 0;
+    let mut down = false;
+    for component in target.split('/') {
+        match component {
+            "" | "." => {}
+            ".." if down => return Err(format!("`..` after a folder in link target {:?}", target)),
+            ".." => ups += 1,
+            _ => down = true,
+        }
+    }
+    Ok(ups)
+}
+
+/// checks name and link target of an ACK_REQ entry
+pub fn check_entry(fm: &FileMeta) -> Result<(), String> {
+    check_name(&fm.name)?;
+    match &fm.link {
+        Some(target) => check_link(&fm.name, target),
+        None => Ok(()),
+    }
+}
+
+/// where a file gets written to until it's complete
+pub fn part_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".part");
+    path.with_file_name(name)
+}
+
+/// the directory received entries get stored in
+pub struct DownloadDir {
+    /// canonical path
+    root: PathBuf,
+    /// keep the `.part` files of interrupted transmissions to resume them later
+    pub keep_partial: bool,
+}
+
+impl DownloadDir {
+    /// creates `dir` if it doesn't exist
+    pub fn new(dir: &Path, keep_partial: bool) -> io::Result<DownloadDir> {
+        std::fs::create_dir_all(dir)?;
+        Ok(DownloadDir {
+            root: dir.canonicalize()?,
+            keep_partial,
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// local path of the checked `name`, fails if it would end up outside of the directory
+    pub fn path(&self, name: &str) -> io::Result<PathBuf> {
+        check_name(name).map_err(|e| io::Error::new(ErrorKind::InvalidInput, format!("{:?}: {}", name, e)))?;
+
+        let mut path = self.root.clone();
+        path.extend(name.split('/'));
+
+        // the deepest existing part decides where the path really goes (symlinks)
+        let existing = path
+            .ancestors()
+            .find(|p| p.symlink_metadata().is_ok())
+            .unwrap_or(&self.root)
+            .canonicalize()?;
+        if !existing.starts_with(&self.root) {
+            return Err(io::Error::new(
+                ErrorKind::PermissionDenied,
+                format!("{:?} leads outside of {:?}", name, self.root),
+            ));
+        }
+        Ok(path)
+    }
+
+    /// checks the symlink at `path` to `target` again with the real folder it's in,
+    /// that folder might be reached through other links
+    pub fn check_link_at(&self, path: &Path, target: &str) -> io::Result<()> {
+        let parent = path.parent().unwrap_or(&self.root).canonicalize()?;
+        let depth = parent.strip_prefix(&self.root).map(|p| p.components().count());
+        let ups = link_ups(target).map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+        match depth {
+            Ok(depth) if ups <= depth => Ok(()),
+            _ => Err(io::Error::new(
+                ErrorKind::PermissionDenied,
+                format!("link target {:?} leads outside of {:?}", target, self.root),
+            )),
+        }
+    }
+
+    /// picks the path a file gets stored at, `None` if it should be skipped.
+    /// `taken` are the paths (and `.part` files) other files being received got already,
+    /// these never get overwritten
+    pub fn resolve(&self, name: &str, conflict: Conflict, taken: &mut HashSet<PathBuf>) -> io::Result<Option<PathBuf>> {
+        let path = self.path(name)?;
+        let free = |p: &Path| !taken.contains(p) && p.symlink_metadata().is_err();
+
+        let path = if free(&path) {
+            path
+        } else {
+            match conflict {
+                Conflict::Overwrite if !taken.contains(&path) => path,
+                Conflict::Skip => return Ok(None),
+                _ => {
+                    let (stem, ext) = match name.rsplit('/').next().unwrap_or(name).rsplit_once('.') {
+                        Some((stem, ext)) if !stem.is_empty() => (stem.to_string(), format!(".{}", ext)),
+                        _ => (name.rsplit('/').next().unwrap_or(name).to_string(), String::new()),
+                    };
+                    (1..)
+                        .map(|i| path.with_file_name(format!("{} ({}){}", stem, i, ext)))
+                        .find(|p| free(p))
+                        .unwrap_or(path)
+                }
+            }
+        };
+
+        taken.insert(part_path(&path));
+        taken.insert(path.clone());
+        Ok(Some(path))
+    }
+}
+
+#[test]
+fn test_names_and_conflicts() -> io::Result<()> {
+    for bad in ["", "/etc/passwd", "../x", "a/../../x", "a//b", "./a", "a\0b", "C:\\x", "a\\..\\b"] {
+        assert!(check_name(bad).is_err(), "{:?}", bad);
+    }
+    for good in ["a.txt", "dir/sub/file.tar.gz", ".bashrc", "console.log", "..hidden"] {
+        assert_eq!(check_name(good), Ok(()), "{:?}", good);
+    }
+    // only windows can't store these
+    for name in ["con", "dir/NUL.txt", "src/aux.c", "include/con.h", "12:00.txt"] {
+        assert_eq!(check_name(name).is_err(), cfg!(windows), "{:?}", name);
+    }
+    assert_eq!(check_link("dir/sub/link", "../../a.txt"), Ok(()));
+    assert_eq!(check_link("dir/link", "./sub/x"), Ok(()));
+    for bad in ["../a.txt", "/etc/passwd", "sub/../x", ""] {
+        assert!(check_link("link", bad).is_err(), "{:?}", bad);
+    }
+
+    let root = std::env::temp_dir().join("sfshare_paths_test");
+    let _ = std::fs::remove_dir_all(&root);
+    std::fs::create_dir_all(root.join("dir"))?;
+    std::fs::write(root.join("dir/a.txt"), b"old")?;
+    let dir = DownloadDir::new(&root, true)?;
+    let root = dir.root.clone();
+
+    let mut taken = HashSet::new();
+    let rename = |taken: &mut HashSet<PathBuf>| dir.resolve("dir/a.txt", Conflict::Rename, taken);
+    assert_eq!(rename(&mut taken)?, Some(root.join("dir/a (1).txt")));
+    // the next file with the same name doesn't get the same path
+    assert_eq!(rename(&mut taken)?, Some(root.join("dir/a (2).txt")));
+    assert_eq!(dir.resolve("dir/a.txt", Conflict::Skip, &mut taken)?, None);
+    let mut other = HashSet::new();
+    assert_eq!(dir.resolve("dir/a.txt", Conflict::Overwrite, &mut other)?, Some(root.join("dir/a.txt")));
+    assert_eq!(dir.resolve("dir/a.txt", Conflict::Overwrite, &mut other)?, Some(root.join("dir/a (1).txt")));
+    assert_eq!(dir.resolve("new/b", Conflict::Skip, &mut taken)?, Some(root.join("new/b")));
+    assert_eq!(part_path(&root.join("dir/a (1).txt")), root.join("dir/a (1).txt.part"));
+    // a file named like the .part of another one
+    assert_eq!(dir.resolve("new/b.part", Conflict::Rename, &mut taken)?, Some(root.join("new/b (1).part")));
+
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::symlink(std::env::temp_dir(), root.join("out"))?;
+        assert!(dir.path("out/x").is_err());
+        // `up` is the download dir itself, one level less than its name says
+        std::os::unix::fs::symlink(".", root.join("up"))?;
+        assert_eq!(check_link("up/link", "../x"), Ok(()));
+        assert!(dir.check_link_at(&root.join("up/link"), "../x").is_err());
+        assert!(dir.check_link_at(&root.join("dir/link"), "../x").is_ok());
+    }
+    std::fs::remove_dir_all(&root)
+}
@@ -0,0 +1,111 @@
+//! Rules for answering transmission requests without asking the user.
+
+use crate::paths::Conflict;
+use crate::transport::{EntryKind, FileMeta};
+
+use std::net::IpAddr;
+
+#[derive(Debug, Clone, Default)]
+pub struct AcceptPolicy {
+    /// accept every request passing the other rules without asking
+    pub yes: bool,
+    /// only these peers may send, everyone if empty.
+    /// connections from this machine (unix sockets, stdio) aren't affected
+    pub allow: Vec<IpAddr>,
+    /// max total size of a request in bytes
+    pub max_size: Option<u64>,
+    pub max_files: Option<usize>,
+    /// accept requests of senders that didn't encrypt the connection
+    pub allow_unencrypted: bool,
+    /// what happens to files that exist already
+    pub conflict: Conflict,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Decision {
+    Accept,
+    /// reject with the reason send to the sender
+    Reject(String),
+    Ask,
+}
+
+impl AcceptPolicy {
+    /// `peer` is `None` for connections from this machine
+    pub fn decide(&self, peer: Option<IpAddr>, files: &[FileMeta]) -> Decision {
+        if let Some(peer) = peer {
+            // ipv4 peers show up as ::ffff:a.b.c.d on the dual-stack listener
+            let peer = peer.to_canonical();
+            if !self.allow.is_empty() && !self.allow.iter().any(|a| a.to_canonical() == peer) {
+                return Decision::Reject(format!("{} is not allowed to send files", peer));
+            }
+        }
+
+        // the sizes come from the sender, together they might not fit
+        let size = match files.iter().try_fold(0u64, |acc, f| acc.checked_add(f.size)) {
+            Some(size) => size,
+            None => return Decision::Reject(String::from("the files are larger than any disk")),
+        };
+        if let Some(max_size) = self.max_size {
+            if size > max_size {
+                return Decision::Reject(format!(
+                    "{:.3}mb is more than the limit of {:.3}mb",
+                    size as f64 / 1_000_000.0,
+                    max_size as f64 / 1_000_000.0
+                ));
+            }
+        }
+
+        let count = files.iter().filter(|f| f.kind == EntryKind::File).count();
+        if let Some(max_files) = self.max_files {
+            if count > max_files {
+                return Decision::Reject(format!(
+                    "{} files are more than the limit of {}",
+                    count, max_files
+                ));
+            }
+        }
+
+        if self.yes {
+            Decision::Accept
+        } else {
+            Decision::Ask
+        }
+    }
+}
+
+#[test]
+fn test_policy_decide() {
+    let file = |size| FileMeta {
+        size,
+        id: 0,
+        name: String::from("f"),
+        kind: EntryKind::File,
+        path: None,
+        mtime: None,
+        mode: None,
+        link: None,
+    };
+    let local: Option<IpAddr> = "::ffff:127.0.0.1".parse().ok();
+
+    let policy = AcceptPolicy {
+        yes: true,
+        allow: vec!["127.0.0.1".parse().unwrap()],
+        max_size: Some(100),
+        max_files: Some(2),
+        ..AcceptPolicy::default()
+    };
+
+    assert_eq!(policy.decide(local, &[file(50), file(50)]), Decision::Accept);
+    assert!(matches!(policy.decide(local, &[file(101)]), Decision::Reject(_)));
+    assert!(matches!(policy.decide(local, &[file(1), file(1), file(1)]), Decision::Reject(_)));
+    assert!(matches!(
+        policy.decide("10.0.0.2".parse().ok(), &[file(1)]),
+        Decision::Reject(_)
+    ));
+    assert_eq!(policy.decide(None, &[file(1)]), Decision::Accept);
+
+    let ask = AcceptPolicy::default();
+    assert_eq!(ask.decide(local, &[file(1_000_000)]), Decision::Ask);
+    // sizes wrapping around to something small
+    assert!(matches!(ask.decide(local, &[file(u64::MAX), file(2)]), Decision::Reject(_)));
+}
@@ -0,0 +1,137 @@
+//! Progress display for several transfers at once.
+//!
+//! Every transfer owns one line at the bottom of the terminal. Messages get printed above
+//! these lines, so logs of one connection don't overwrite the progress of another.
+
+use crossterm::cursor::MoveUp;
+use crossterm::queue;
+use crossterm::style::Print;
+use crossterm::terminal::{self, Clear, ClearType};
+
+use sfshare::Peer;
+
+use std::io::{stderr, stdout, IsTerminal, Write};
+use std::sync::{Mutex, MutexGuard};
+
+#[derive(Default)]
+struct Board {
+    /// (sender, line) in order of appearance
+    lines: Vec<(Peer, String)>,
+    /// lines currently on the screen
+    drawn: u16,
+    /// while someone answers a prompt, nothing gets drawn
+    paused: bool,
+    /// everything goes to stderr, stdout carries the connection
+    stderr: bool,
+    /// only errors get printed, stderr goes through a pipe to the sender
+    quiet: bool,
+}
+
+impl Board {
+    fn out(&self) -> Box<dyn Write> {
+        match self.stderr {
+            true => Box::new(stderr()),
+            false => Box::new(stdout()),
+        }
+    }
+
+    fn clear(&mut self) {
+        if self.drawn > 0 {
+            let _ = queue!(self.out(), MoveUp(self.drawn), Clear(ClearType::FromCursorDown));
+            self.drawn = 0;
+        }
+    }
+
+    fn redraw(&mut self) {
+        if self.paused || self.quiet {
+            return;
+        }
+        self.clear();
+
+        // wrapped lines would break moving up
+        let width = terminal::size().map(|(w, _)| w as usize).unwrap_or(80).max(2) - 1;
+        let mut out = self.out();
+        for (_, line) in &self.lines {
+            let line: String = line.chars().take(width).collect();
+            let _ = queue!(out, Print(line), Print("\n"));
+        }
+        self.drawn = self.lines.len() as u16;
+        let _ = out.flush();
+    }
+}
+
+#[derive(Default)]
+pub struct Progress {
+    board: Mutex<Board>,
+}
+
+impl Progress {
+    /// draws on stderr instead of stdout. if that isn't a terminal (ssh passes it on to the sender)
+    /// only errors get printed, they would mess up the display of the sender otherwise
+    pub fn on_stderr() -> Progress {
+        Progress {
+            board: Mutex::new(Board {
+                stderr: true,
+                quiet: !stderr().is_terminal(),
+                ..Board::default()
+            }),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Board> {
+        // a panicking connection thread shouldn't take the display down with it
+        self.board.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// sets the line of the transfer from `id`, adds it if it's new
+    pub fn update(&self, id: &Peer, line: String) {
+        let mut board = self.lock();
+        match board.lines.iter_mut().find(|(i, _)| i == id) {
+            Some(l) => l.1 = line,
+            None => board.lines.push((id.clone(), line)),
+        }
+        board.redraw();
+    }
+
+    /// removes the line of the transfer from `id`
+    pub fn finish(&self, id: &Peer) {
+        let mut board = self.lock();
+        let before = board.lines.len();
+        board.lines.retain(|(i, _)| i != id);
+        if board.lines.len() != before {
+            board.redraw();
+        }
+    }
+
+    /// prints `text` above the progress lines
+    pub fn message(&self, text: &str) {
+        let mut board = self.lock();
+        if board.quiet {
+            return;
+        }
+        board.clear();
+        let _ = writeln!(board.out(), "{}", text);
+        board.redraw();
+    }
+
+    /// like `message`, to stderr
+    pub fn error(&self, text: &str) {
+        let mut board = self.lock();
+        board.clear();
+        eprintln!("{}", text);
+        board.redraw();
+    }
+
+    /// hides the progress lines until `resume` is called, the terminal belongs to a prompt then
+    pub fn pause(&self) {
+        let mut board = self.lock();
+        board.clear();
+        board.paused = true;
+    }
+
+    pub fn resume(&self) {
+        let mut board = self.lock();
+        board.paused = false;
+        board.redraw();
+    }
+}
@@ -1,245 +1,1166 @@
+use crate::compress::{self, Compression};
+use crate::conn::{self, Connection, Endpoint, Listen, Peer};
+use crate::crypto::{self, SecureReader, SecureWriter};
+use crate::discovery;
+use crate::error::{Error, Result};
+use crate::event::{self, Ask, Event, Handler, Request};
+use crate::hash::{self, HashAlgo, Hasher};
+use crate::paths::{self, Conflict, DownloadDir};
+use crate::policy::{AcceptPolicy, Decision};
 use crate::transport;
-use crate::transport::{FileMeta, Parsed};
+use crate::transport::{caps, EntryKind, FileMeta, Hello, Limits, Parsed, PartialFile};
 
-use crossterm::style::{Colorize, Print};
-use crossterm::cursor::{MoveDown, MoveUp};
-use crossterm::terminal::{Clear, ClearType};
-use crossterm::{queue};
-
-use std::collections::{HashMap};
-use std::fs::File;
-use std::io::{BufReader, BufWriter, Write, stdout};
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::iter::FromIterator;
-use std::net::Ipv6Addr;
-use std::path::PathBuf;
-use std::{
-    io,
-    net::{IpAddr, TcpListener},
-};
-
-fn tcp_handler() -> io::Result<()> {
-    #[cfg(debug_assertions)]
-    println!("tcp_handler()");
-
-    let listener = TcpListener::bind((IpAddr::V6(Ipv6Addr::LOCALHOST), 5123u16))?; // 2
-    let mut incoming = listener.incoming();
-
-    // get adresses to connect to
-    println!("{}", "Ip Adresses to connect to".black().on_green());
-    for adapter in
-        ipconfig::get_adapters().map_err(|_| io::Error::from(io::ErrorKind::NotConnected))?
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::io;
+
+/// path the entry gets written to on this machine, picked when the request got accepted
+fn local_path(fm: &FileMeta) -> io::Result<PathBuf> {
+    fm.path
+        .clone()
+        .ok_or_else(|| io::Error::other(format!("no local path for {}", fm.name)))
+}
+
+/// the `.part` file the entry gets written to until its hash matched
+fn staging_path(fm: &FileMeta) -> io::Result<PathBuf> {
+    Ok(paths::part_path(&local_path(fm)?))
+}
+
+/// the transmission of `fm` stopped in the middle, returns if its `.part` file is kept to resume it.
+/// ranges of split files arrive out of order, these always start over
+fn abandon(fm: &FileMeta, split: bool, dir: &DownloadDir) -> io::Result<bool> {
+    if dir.keep_partial && !split {
+        return Ok(true);
+    }
+    match std::fs::remove_file(staging_path(fm)?) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(false),
+    }
+}
+
+/// sets the modification time and permissions of a received entry
+fn apply_metadata(path: &Path, fm: &FileMeta) -> io::Result<()> {
+    if let Some(mtime) = fm.mtime {
+        let file = match fm.kind {
+            EntryKind::Dir => File::open(path)?,
+            _ => OpenOptions::new().write(true).open(path)?,
+        };
+        file.set_modified(mtime)?;
+    }
+    // after the mtime, read only files can't be opened for writing anymore
+    #[cfg(unix)]
+    if let Some(mode) = fm.mode {
+        use std::os::unix::fs::PermissionsExt;
+        // no setuid etc. from the network
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o777))?;
+    }
+    Ok(())
+}
+
+/// creates the symlink `fm`, an existing file at its path only gets there with `Conflict::Overwrite`
+fn create_symlink(fm: &FileMeta, dir: &DownloadDir) -> io::Result<()> {
+    let path = local_path(fm)?;
+    let target = fm.link.as_deref().unwrap_or_default();
+    dir.check_link_at(&path, target)?;
+    if path.symlink_metadata().is_ok_and(|m| !m.is_dir()) {
+        std::fs::remove_file(&path)?;
+    }
+    #[cfg(unix)]
+    return std::os::unix::fs::symlink(target, &path);
+    #[cfg(not(unix))]
+    return Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("can't create symlinks to {:?} on this system", target),
+    ));
+}
+
+/// picks the local paths of the accepted entries, files that exist already get handled by
+/// `conflict`. `taken` gets the paths of the files, they must be released when the request is done.
+/// returns the names of skipped files, they get removed from `accepted`
+fn assign_paths(
+    req: &mut [FileMeta],
+    accepted: &mut HashSet<u32>,
+    dir: &DownloadDir,
+    conflict: Conflict,
+    taken: &mut HashSet<PathBuf>,
+) -> io::Result<Vec<String>> {
+    let mut skipped = Vec::new();
+    for e in req.iter_mut() {
+        match e.kind {
+            EntryKind::Dir => e.path = Some(dir.path(&e.name)?),
+            EntryKind::File if !accepted.contains(&e.id) => {}
+            EntryKind::File | EntryKind::Symlink => match dir.resolve(&e.name, conflict, taken)? {
+                Some(path) => e.path = Some(path),
+                None => {
+                    accepted.remove(&e.id);
+                    skipped.push(e.name.clone());
+                }
+            },
+        }
+    }
+    Ok(skipped)
+}
+
+/// creates the file and all missing parent directories
+fn create_file(path: &PathBuf) -> io::Result<File> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    File::create(path)
+}
+
+/// opens `path` for positional writes without truncating it, ranges arrive in any order
+fn open_for_ranges(path: &Path) -> io::Result<File> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    OpenOptions::new().write(true).create(true).truncate(false).open(path)
+}
+
+#[cfg(unix)]
+fn write_at(file: &File, data: &[u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.write_all_at(data, offset)
+}
+
+#[cfg(windows)]
+fn write_at(file: &File, mut data: &[u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !data.is_empty() {
+        let n = file.seek_write(data, offset)?;
+        if n == 0 {
+            return Err(io::Error::from(io::ErrorKind::WriteZero));
+        }
+        data = &data[n..];
+        offset += n as u64;
+    }
+    Ok(())
+}
+
+/// looks for data of an earlier, interrupted transmission of `fm`.
+/// returns the hasher state after the partial data too, to continue from there
+fn partial_file(fm: &FileMeta, algo: HashAlgo) -> io::Result<Option<(PartialFile, Hasher)>> {
+    let mut file = match File::open(staging_path(fm)?) {
+        Ok(f) => f,
+        Err(_) => return Ok(None),
+    };
+
+    let len = file.metadata()?.len();
+    if len == 0 || len > fm.size {
+        return Ok(None);
+    }
+
+    let mut hasher = Hasher::new(algo);
+    hasher.update_from(&mut file, len)?;
+
+    let partial = PartialFile {
+        id: fm.id,
+        offset: len,
+        digest: hasher.clone().finish(),
+    };
+    Ok(Some((partial, hasher)))
+}
+
+/// state all connections share
+struct Shared {
+    policy: AcceptPolicy,
+    limits: Limits,
+    dir: DownloadDir,
+    /// held while a request is shown, so the user gets asked about one request at a time
+    prompt: Mutex<()>,
+    events: Handler,
+    /// decides about requests the policy doesn't answer, without it they get rejected
+    ask: Option<Ask>,
+    /// accepted requests extra streams of the sender can join
+    transfers: Mutex<Vec<Arc<Transfer>>>,
+    /// paths (and `.part` files) of all running requests, two senders never write the same file
+    receiving: Mutex<HashSet<PathBuf>>,
+}
+
+/// files of an accepted request, extra streams of the sender write ranges into them
+struct Transfer {
+    /// `None` for unencrypted connections, nothing can join them
+    stream_key: Option<[u8; 32]>,
+    /// protocol version and features agreed with the sender
+    hello: Hello,
+    algo: HashAlgo,
+    compression: Compression,
+    /// id -> (local path, size) of the accepted files
+    files: HashMap<u32, (PathBuf, u64)>,
+    ranges: Mutex<RangeState>,
+    changed: Condvar,
+}
+
+/// how the main connection handles a file of a transfer
+#[derive(Clone, Copy, PartialEq)]
+enum Phase {
+    /// the data comes over the main connection
+    Main,
+    /// announced with FILE_SPLIT, the extra streams send its ranges
+    Split,
+    /// all ranges arrived or the file was given up
+    Done,
+}
+
+#[derive(Default)]
+struct RangeState {
+    /// bytes the extra streams wrote per file
+    written: HashMap<u32, u64>,
+    /// files the main connection started, only split ones take ranges
+    phases: HashMap<u32, Phase>,
+    /// ranges being written right now
+    writing: usize,
+    /// the main connection is done with the request, no file takes ranges anymore
+    closed: bool,
+    /// a stream failed, split files won't be complete
+    broken: bool,
+    /// extra streams still sending ranges, missing bytes won't arrive once all are closed
+    streams: usize,
+}
+
+impl Transfer {
+    fn new(
+        stream_key: Option<[u8; 32]>,
+        hello: Hello,
+        algo: HashAlgo,
+        compression: Compression,
+        req: &[FileMeta],
+    ) -> Transfer {
+        Transfer {
+            stream_key,
+            hello,
+            algo,
+            compression,
+            files: req
+                .iter()
+                .filter(|e| e.kind == EntryKind::File)
+                .filter_map(|e| e.path.as_deref().map(|p| (e.id, (paths::part_path(p), e.size))))
+                .collect(),
+            ranges: Mutex::new(RangeState::default()),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// the main connection started file `id`
+    fn set_phase(&self, id: u32, phase: Phase) {
+        lock(&self.ranges).phases.insert(id, phase);
+        self.changed.notify_all();
+    }
+
+    /// the main connection is done, ranges of any file get rejected
+    fn close(&self) {
+        lock(&self.ranges).closed = true;
+        self.changed.notify_all();
+    }
+
+    /// waits up to `timeout` for the main connection to start file `id`, FILE_SPLIT and
+    /// the first range travel over different connections. returns if it takes ranges
+    fn wait_split(&self, id: u32, timeout: Duration) -> bool {
+        let state = lock(&self.ranges);
+        let (state, _) = self
+            .changed
+            .wait_timeout_while(state, timeout, |s| !s.closed && !s.phases.contains_key(&id))
+            .unwrap_or_else(|e| e.into_inner());
+        !state.closed && state.phases.get(&id) == Some(&Phase::Split)
+    }
+
+    /// a stream wants to write a block of file `id`, false if the file doesn't take ranges (anymore).
+    /// `end_write` follows if it may
+    fn begin_write(&self, id: u32) -> bool {
+        let mut state = lock(&self.ranges);
+        let split = !state.closed && state.phases.get(&id) == Some(&Phase::Split);
+        if split {
+            state.writing += 1;
+        }
+        split
+    }
+
+    fn end_write(&self, id: u32, len: u64) {
+        let mut state = lock(&self.ranges);
+        state.writing -= 1;
+        *state.written.entry(id).or_insert(0) += len;
+        self.changed.notify_all();
+    }
+
+    /// no more ranges of file `id` get written, waits for the blocks being written right now
+    fn finish_split(&self, id: u32) {
+        let mut state = lock(&self.ranges);
+        state.phases.insert(id, Phase::Done);
+        while state.writing > 0 {
+            state = self.changed.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    fn join_stream(&self) {
+        lock(&self.ranges).streams += 1;
+    }
+
+    /// an extra stream is closed, `failed` if it didn't end cleanly
+    fn leave_stream(&self, failed: bool) {
+        let mut state = lock(&self.ranges);
+        state.streams -= 1;
+        state.broken |= failed;
+        self.changed.notify_all();
+    }
+
+    /// waits up to `timeout` until the streams wrote `len` bytes of file `id`.
+    /// returns the bytes written so far and if waiting longer is pointless
+    fn wait_written(&self, id: u32, len: u64, timeout: Duration) -> (u64, bool) {
+        let state = lock(&self.ranges);
+        let (state, _) = self
+            .changed
+            .wait_timeout_while(state, timeout, |s| {
+                !s.broken && s.streams > 0 && s.written.get(&id).copied().unwrap_or(0) < len
+            })
+            .unwrap_or_else(|e| e.into_inner());
+
+        let written = state.written.get(&id).copied().unwrap_or(0);
+        (written, state.broken || state.streams == 0 || written >= len)
+    }
+}
+
+/// waits for requests and stores the accepted files in its download directory
+pub struct Receiver {
+    endpoint: Endpoint,
+    /// device name announced to senders looking for receivers, `None` doesn't announce.
+    /// only tcp listeners get announced
+    name: Option<String>,
+    policy: AcceptPolicy,
+    limits: Limits,
+    dir: DownloadDir,
+    events: Handler,
+    ask: Option<Ask>,
+}
+
+impl Receiver {
+    /// listens on all interfaces on the default port without announcing itself.
+    /// requests get rejected unless the policy accepts them or `on_ask` is set
+    pub fn new(dir: DownloadDir) -> Receiver {
+        Receiver {
+            endpoint: Endpoint::Tcp(SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), transport::DEFAULT_PORT)),
+            name: None,
+            policy: AcceptPolicy::default(),
+            limits: Limits::default(),
+            dir,
+            events: event::ignore(),
+            ask: None,
+        }
+    }
+
+    /// only listen on this address, the unspecified ipv6 address accepts ipv4 connections too
+    pub fn bind(mut self, bind: IpAddr) -> Receiver {
+        self.endpoint = Endpoint::Tcp(SocketAddr::new(bind, self.tcp_addr().port()));
+        self
+    }
+
+    /// tcp port to listen on, 0 picks a free one (see `Event::Listening`)
+    pub fn port(mut self, port: u16) -> Receiver {
+        self.endpoint = Endpoint::Tcp(SocketAddr::new(self.tcp_addr().ip(), port));
+        self
+    }
+
+    /// listens on a unix domain socket or stdin/stdout instead of tcp
+    pub fn endpoint(mut self, endpoint: Endpoint) -> Receiver {
+        self.endpoint = endpoint;
+        self
+    }
+
+    /// tcp address set so far, the default one if another endpoint is set
+    fn tcp_addr(&self) -> SocketAddr {
+        match self.endpoint {
+            Endpoint::Tcp(addr) => addr,
+            _ => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), transport::DEFAULT_PORT),
+        }
+    }
+
+    /// answers senders looking for receivers in the local network with `name`
+    pub fn announce(mut self, name: String) -> Receiver {
+        self.name = Some(name);
+        self
+    }
+
+    pub fn policy(mut self, policy: AcceptPolicy) -> Receiver {
+        self.policy = policy;
+        self
+    }
+
+    /// largest packets senders may send, connections sending more get closed.
+    /// unlike the policy these don't reject requests politely
+    pub fn limits(mut self, limits: Limits) -> Receiver {
+        self.limits = limits;
+        self
+    }
+
+    pub fn on_event<F: Fn(&Event) + Send + Sync + 'static>(mut self, handler: F) -> Receiver {
+        self.events = Arc::new(handler);
+        self
+    }
+
+    /// gets asked about requests the policy doesn't decide, one request at a time.
+    /// returns the ids of the accepted files, `None` or an empty set rejects the request
+    pub fn on_ask<F>(mut self, ask: F) -> Receiver
+    where
+        F: Fn(&Request) -> Option<HashSet<u32>> + Send + Sync + 'static,
     {
-        println!("{:30} | {}", adapter.adapter_name(), adapter.description());
+        self.ask = Some(Arc::new(ask));
+        self
+    }
+
+    /// handles connections until the listener fails, every connection in its own thread
+    pub fn run(self) -> Result<()> {
+        let mut listener = conn::listen(&self.endpoint).map_err(Error::Network)?;
+        self.run_on(&mut *listener)
+    }
+
+    /// like `run` with connections of `listener`. returns once it has no more connections
+    /// and their requests are done, with the error of the first one failing
+    pub fn run_on<L: Listen + ?Sized>(self, listener: &mut L) -> Result<()> {
+        //  tasks needed:
+        //  - listener : accepts connections, one thread per sender
+        //  - prompt: ask for confirmation of receiving, one request at a time
+        //  - discovery: answers senders looking for receivers in the network
+        let addr = listener.endpoint()?;
+        // answers as long as this runs
+        let _announcer = match (self.name, &addr) {
+            (Some(name), Endpoint::Tcp(addr)) => discovery::spawn_announcer(name, addr.port(), Arc::clone(&self.events)),
+            _ => None,
+        };
+
+        let shared = Arc::new(Shared {
+            policy: self.policy,
+            limits: self.limits,
+            dir: self.dir,
+            prompt: Mutex::new(()),
+            events: self.events,
+            ask: self.ask,
+            transfers: Mutex::new(Vec::new()),
+            receiving: Mutex::new(HashSet::new()),
+        });
+        (shared.events)(&Event::Listening { addr });
+
+        // every connection gets its own thread, a slow sender or an open prompt doesn't block the others
+        let mut running: Vec<thread::JoinHandle<Result<()>>> = Vec::new();
+        let mut res = Ok(());
+        while let Some(conn) = listener.accept() {
+            let conn = match conn {
+                Ok(c) => c,
+                Err(e) => {
+                    (shared.events)(&Event::Error {
+                        peer: None,
+                        error: Error::Network(e),
+                    });
+                    // errors like too many open files don't go away right away
+                    thread::sleep(ACCEPT_BACKOFF);
+                    continue;
+                }
+            };
 
-        for ip in adapter.ip_addresses() {
-            match ip {
-                //IpAddr::V4(addr) => println!(" IPv4 > {}:5123", addr),
-                IpAddr::V6(addr) => println!(" IPv6 > [{}]:5123", addr),
-                _ => {}
+            let shared = Arc::clone(&shared);
+            let (finished, open): (Vec<_>, Vec<_>) = running.drain(..).partition(|t| t.is_finished());
+            running = open;
+            for t in finished {
+                res = res.and(join_connection(t));
             }
+            running.push(thread::spawn(move || {
+                let peer = conn.peer();
+                let res = handle_connection(conn, &shared);
+                if let Err(error) = &res {
+                    (shared.events)(&Event::Error {
+                        peer: Some(peer.clone()),
+                        error: error.clone(),
+                    });
+                }
+                (shared.events)(&Event::Closed { peer });
+                res
+            }));
+        }
+
+        for t in running {
+            res = res.and(join_connection(t));
         }
+        res
     }
+}
 
-    println!("Waiting for files...");
+/// the result of a finished connection thread
+fn join_connection(t: thread::JoinHandle<Result<()>>) -> Result<()> {
+    t.join().unwrap_or_else(|_| Err(Error::Io(io::Error::other("connection thread panicked"))))
+}
 
-    'new_con: while let Some(stream) = incoming.next() {
-        // 3
-        let mut stream = stream?;
+/// handles packets of one sender until the connection is closed or a request is done
+fn handle_connection(conn: Box<dyn Connection>, shared: &Shared) -> Result<()> {
+    let peer = conn.peer();
+    let (read, write) = conn.split()?;
+    let mut reader = BufReader::new(SecureReader::new(read));
+    let mut stream = SecureWriter::new(write);
+    // verification code etc. of the encrypted connection
+    let mut session: Option<crypto::Session> = None;
+    // protocol version and features both sides support, set by HELLO
+    let mut hello: Option<Hello> = None;
+    // KEY_COMMIT of the sender and our key pair, until the sender reveals its key
+    let mut key_commit: Option<([u8; 32], crypto::Handshake)> = None;
 
-        let mut reader = BufReader::new(stream.try_clone()?);
+    let log = |text: &str| {
+        (shared.events)(&Event::Message {
+            peer: peer.clone(),
+            text: text.to_string(),
+        })
+    };
 
-        println!("new connection");
+    (shared.events)(&Event::Connected { peer: peer.clone() });
 
-        'new_packet: loop {
-            println!("waiting for next packet");
+    loop {
+        let parsed = match transport::parse_with(&mut reader, &shared.limits) {
+            Ok(p) => p,
+            Err(e) => match e.kind() {
+                io::ErrorKind::UnexpectedEof => {
+                    // connection is closed
+                    log("Connection closed");
+                    return Ok(());
+                }
+                _ => return Err(e.into()),
+            },
+        };
 
-            let parsed = match transport::parse(&mut reader) {
-                Ok(p) => p,
-                Err(e) => {
-                    match e.kind() {
-                        io::ErrorKind::UnexpectedEof => {
-                            // connection is closed
-                            println!("Connection closed");
-                            continue 'new_con;
-                        }
-                        io::ErrorKind::InvalidData => {
-                            eprintln!("Unknown packet / invalid data: {}", e);
-                            continue 'new_packet;
-                        }
-                        _ => {
-                            eprintln!("Unknown error {} : try again or contact developer!", e);
-                            return Err(e);
+        let agreed = match (&parsed, hello) {
+            (transport::Parsed::Hello(_), None) | (transport::Parsed::Ping, _) => Hello::ours(),
+            (_, Some(h)) => h,
+            (p, None) => {
+                return Err(Error::Protocol(format!("sender started with {} instead of HELLO", p.name())));
+            }
+        };
+
+        match parsed {
+            transport::Parsed::Ping => {
+                return Err(Error::Incompatible(String::from(
+                    "Sender runs an old sfshare version without protocol versions, it needs an update",
+                )));
+            }
+            transport::Parsed::Hello(peer) => {
+                if hello.is_some() {
+                    return Err(Error::Protocol(String::from("sender said HELLO twice")));
+                }
+                // answer anyway, so the sender can tell its user what's wrong
+                transport::send_slice(&mut stream, transport::Parsed::HelloRes(Hello::ours()).to_buf().as_ref())?;
+                match agreed.agree(&peer) {
+                    Ok(h) => hello = Some(h),
+                    Err(e) => return Err(Error::Incompatible(format!("Can't talk to the sender: {}", e))),
+                }
+            }
+            transport::Parsed::KeyCommit(commitment) => {
+                if session.is_some() || key_commit.is_some() {
+                    return Err(Error::Protocol(String::from("sender started a second key exchange")));
+                }
+                let handshake = crypto::Handshake::new();
+                transport::send_slice(
+                    &mut stream,
+                    transport::Parsed::KeyExchange(handshake.public_key()).to_buf().as_ref(),
+                )?;
+                key_commit = Some((commitment, handshake));
+            }
+            transport::Parsed::KeyExchange(peer_key) => {
+                let (commitment, handshake) = key_commit.take().ok_or_else(|| {
+                    Error::Protocol(String::from("sender sent its key without KEY_COMMIT"))
+                })?;
+                if crypto::key_commitment(&peer_key) != commitment {
+                    return Err(Error::Protocol(String::from("the key of the sender doesn't match its KEY_COMMIT")));
+                }
+
+                let key_exchange_failed = |e: io::Error| Error::Protocol(format!("key exchange failed: {}", e));
+                let keys = handshake.finish(crypto::Role::Receiver, peer_key).map_err(key_exchange_failed)?;
+                stream.start_encryption(keys.send);
+                crypto::start_decryption(&mut reader, keys.recv).map_err(key_exchange_failed)?;
+                session = Some(keys.session);
+            }
+            transport::Parsed::AckReq {
+                files: mut req,
+                hashes,
+                compressions,
+            } => {
+                let invalid = if session.is_none() && !shared.policy.allow_unencrypted {
+                    Some(String::from("the receiver only accepts encrypted connections"))
+                } else if let Some(id) = transport::duplicate_id(&req) {
+                    Some(format!("the request uses file id {} more than once", id))
+                } else if !agreed.has(caps::DIRECTORIES) && req.iter().any(|e| e.kind != EntryKind::File) {
+                    Some(String::from("directories weren't agreed on"))
+                } else if let Some((name, e)) = req.iter().find_map(|e| paths::check_entry(e).err().map(|r| (&e.name, r))) {
+                    Some(format!("invalid name {:?}: {}", name, e))
+                } else {
+                    None
+                };
+                if let Some(reason) = invalid {
+                    (shared.events)(&Event::Rejected {
+                        peer: peer.clone(),
+                        reason: reason.clone(),
+                    });
+                    transport::send_slice(
+                        &mut stream,
+                        transport::Parsed::AckRes {
+                            accept: false,
+                            hash: HashAlgo::negotiate(&hashes),
+                            compression: Compression::None,
+                            reason,
+                            files: Vec::new(),
                         }
+                        .to_buf()
+                        .as_ref(),
+                    )?;
+                    return Ok(());
+                }
+
+                let files_total = req.iter().filter(|e| e.kind == EntryKind::File).count();
+
+                let hash_algo = HashAlgo::negotiate(&hashes);
+                let compression = match agreed.has(caps::COMPRESSION) {
+                    true => Compression::negotiate(&compressions),
+                    false => Compression::None,
+                };
+                if !agreed.has(caps::METADATA) {
+                    for e in req.iter_mut() {
+                        e.mtime = None;
+                        e.mode = None;
                     }
                 }
-            };
-            #[cfg(debug_assertions)]
-            println!("Packet: {:?}", parsed);
-
-            match parsed {
-                transport::Parsed::Ping => {
-                    // send pong back
-                    transport::send_slice(&mut stream, transport::Parsed::Pong.to_buf().as_ref())?;
-                }
-                transport::Parsed::Pong => {
-                    println!("Received pong... why?!");
-                }
-                transport::Parsed::AckReq(mut req) => {
-                    // ask if we ant to receive this
-                    let file_size_sum = req.iter().fold(0, |acc, e| e.size + acc);
-                    let files_total = req.len();
-
-                    println!("{}", "New Transmission Request".yellow().on_dark_magenta());
-
-                    println!(
-                        "\nDo you want to receive {} file{} with a total size of {}mb",
-                        req.len(),
-                        if req.len() > 1 { "s" } else { "" },
-                        file_size_sum as f64 / 1_000_000f64
-                    );
-
-                    let mut l = String::new();
-                    while l.trim() != "y" && l.trim() != "yes" {
-                        println!("[y, yes] / [n, no]");
-                        io::stdin().read_line(&mut l)?;
-                        if l.trim() == "n" || l.trim() == "no" {
-                            println!("You denied the request. Listening for new requests.");
-                            transport::send_slice(
-                                &mut stream,
-                                transport::Parsed::AckRes(false).to_buf().as_ref(),
-                            )?;
-                            continue 'new_con;
+
+                // ask if we want to receive this, one request after the other
+                let decision: std::result::Result<HashSet<u32>, String> = {
+                    let _prompt = lock(&shared.prompt);
+                    let request = Request {
+                        peer: peer.clone(),
+                        files: req.clone(),
+                        code: session.as_ref().map(|s| s.code.clone()),
+                    };
+                    (shared.events)(&Event::Request(request.clone()));
+
+                    match shared.policy.decide(peer.ip(), &req) {
+                        Decision::Accept => {
+                            Ok(req.iter().filter(|e| e.kind == EntryKind::File).map(|e| e.id).collect())
                         }
+                        Decision::Reject(reason) => Err(reason),
+                        Decision::Ask => match shared.ask.as_ref().and_then(|ask| ask(&request)) {
+                            Some(accepted) if !accepted.is_empty() => Ok(accepted),
+                            _ if shared.ask.is_none() => Err(String::from("nobody is there to accept the request")),
+                            // the user denied it
+                            _ => Err(String::new()),
+                        },
                     }
+                };
 
-                    transport::send_slice(
-                        &mut stream,
-                        transport::Parsed::AckRes(true).to_buf().as_ref(),
-                    )?;
+                // where the files go, existing ones might get skipped
+                let decision = decision.and_then(|mut accepted| {
+                    let mut receiving = lock(&shared.receiving);
+                    let mut taken = receiving.clone();
+                    let skipped = assign_paths(&mut req, &mut accepted, &shared.dir, shared.policy.conflict, &mut taken)
+                        .map_err(|e| e.to_string())?;
+                    *receiving = taken;
+                    for name in skipped {
+                        log(&format!("Skipping {}, it exists already", name));
+                    }
+                    Ok(accepted)
+                });
 
-                    let mut files_waiting: HashMap<u32, FileMeta> =
-                        HashMap::from_iter(req.drain(..).map(|e| (e.id, e)));
-
-                    // we need to store the current open file meta data
-                    let mut current_file_meta: Option<FileMeta> = None;
-                    let mut current_file_writer: Option<BufWriter<File>> = None;
-                    let mut current_file_checksum = 0u64;
-
-                    queue!(stdout(), MoveDown(3)).unwrap();
-                    let mut blocks_till_redraw = 0;
-                    let mut bytes_recvd: u64 = 0;
-                    let mut files_received = 0;
-                    loop {
-                        match transport::parse(&mut reader)? {
-                            Parsed::FileBlock { id, data } => {
-                                bytes_recvd += data.len() as u64;
-
-                                if blocks_till_redraw <= 0 || data.is_empty() {
-
-                                    let percent_send = bytes_recvd as f64 / file_size_sum as f64;
-
-                                    queue!(
-                                            stdout(),
-                                            MoveUp(3),
-                                            Clear(ClearType::CurrentLine),
-                                            Print(format!(
-                                                "Copying file with id {} | {}/{}\n",
-                                                id, files_received, files_total
-                                            )),
-                                            Clear(ClearType::CurrentLine),
-                                            Print(format!(
-                                                "{:.3}mb of {:.3}mb send ({:.2}%)\n",
-                                                (bytes_recvd as f64 / 1_000_000.0),
-                                                (file_size_sum as f64 / 1_000_000.0),
-                                                percent_send * 100.0
-                                            )),
-                                            Clear(ClearType::CurrentLine),
-                                            Print(format!(
-                                                "[{}>{}]\n",
-                                                "=".repeat((percent_send * 20.0).floor() as usize),
-                                                " ".repeat(((1.0 - percent_send) * 20.0).ceil() as usize)
-                                            ))
-                                        ).expect("Failed to display loading bar");
-                                    blocks_till_redraw = 20.min(file_size_sum as i64 / (data.len() as i64 * 10 + 1));
-                                }
-                                blocks_till_redraw -= 1;
-                                current_file_checksum = (current_file_checksum
-                                    + data.iter().fold(0u64, |acc, b| acc + *b as u64))
-                                    % transport::CHECKSUM_MOD;
-                                match (&mut current_file_meta, &mut current_file_writer) {
-
-                                    (Some(meta), Some(writer)) => {
-
-
-
-                                        if meta.id != id {
-                                            return Err(io::Error::new(
-                                                io::ErrorKind::PermissionDenied,
-                                                "Wrong file id: not accepted",
-                                            ));
-                                        }
-
-                                        writer.write_all(&data)?;
-                                    }
-                                    _ => {
-
-                                        // create new file if want to receive
-                                        if let Some(mut fm) = files_waiting.remove(&id) {
-                                            assert_eq!(fm.path, None);
-                                            files_received += 1;
-                                            let pbuf = PathBuf::from(format!("./{}", fm.name));
-
-                                            let mut bwriter = BufWriter::new(File::create(&pbuf)?);
-
-                                            bwriter.write_all(&data)?;
-
-                                            fm.path = Some(pbuf);
-                                            current_file_meta = Some(fm);
-                                            current_file_writer = Some(bwriter);
-                                        } else {
-                                            eprintln!("Didn't await this file :/");
-                                            // TODO handle wrong file id
-                                        }
-                                    }
-                                }
+                let accepted = match decision {
+                    Ok(accepted) => accepted,
+                    Err(reason) => {
+                        (shared.events)(&Event::Rejected {
+                            peer: peer.clone(),
+                            reason: reason.clone(),
+                        });
+                        transport::send_slice(
+                            &mut stream,
+                            transport::Parsed::AckRes {
+                                accept: false,
+                                hash: hash_algo,
+                                compression: Compression::None,
+                                reason,
+                                files: Vec::new(),
                             }
-                            Parsed::FileEnd(cs) => {
-                                if cs != current_file_checksum {
-                                    eprintln!(
-                                        "Checksum not identical! calculated: {} | received: {}",
-                                        current_file_checksum, cs
-                                    );
-                                } else {
-                                    println!("File transmission success! Checksum identical");
-                                    current_file_meta = None;
-                                    current_file_writer = None;
-                                    current_file_checksum = 0;
-                                }
-
-                                if files_waiting.is_empty() {
-                                    println!("All files received!");
-                                    continue 'new_con;
-                                }
-                            }
-                            e => {
-                                eprintln!("Received wrong packet :( : {:?}", e);
-                                continue 'new_con;
-                            }
-                        }
+                            .to_buf()
+                            .as_ref(),
+                        )?;
+                        return Ok(());
                     }
+                };
+                (shared.events)(&Event::Accepted {
+                    peer: peer.clone(),
+                    accepted: accepted.len(),
+                    total: files_total,
+                });
+
+                // the paths of the accepted files stay reserved until the request is done
+                let claimed: Vec<PathBuf> = req
+                    .iter()
+                    .filter(|e| e.kind == EntryKind::File && accepted.contains(&e.id))
+                    .filter_map(|e| e.path.clone())
+                    .collect();
+
+                let res = transport::send_slice(
+                    &mut stream,
+                    transport::Parsed::AckRes {
+                        accept: true,
+                        hash: hash_algo,
+                        compression,
+                        reason: String::new(),
+                        files: req
+                            .iter()
+                            .filter(|e| e.kind == EntryKind::File)
+                            .map(|e| (e.id, accepted.contains(&e.id)))
+                            .collect(),
+                    }
+                    .to_buf()
+                    .as_ref(),
+                )
+                .map_err(Error::from)
+                .and_then(|_| {
+                    // only the accepted files get send
+                    req.retain(|e| e.kind != EntryKind::File || accepted.contains(&e.id));
+
+                    // extra streams of the sender can join until the request is done
+                    let transfer = Arc::new(Transfer::new(
+                        session.as_ref().map(|s| s.stream_key),
+                        agreed,
+                        hash_algo,
+                        compression,
+                        &req,
+                    ));
+                    lock(&shared.transfers).push(Arc::clone(&transfer));
+                    let res = receive_files(req, &transfer, &peer, &mut reader, &mut stream, shared);
+                    transfer.close();
+                    lock(&shared.transfers).retain(|t| !Arc::ptr_eq(t, &transfer));
+                    res
+                });
+
+                let mut receiving = lock(&shared.receiving);
+                for path in claimed {
+                    receiving.remove(&paths::part_path(&path));
+                    receiving.remove(&path);
                 }
-                _ => unimplemented!(),
+                return res;
+            }
+            transport::Parsed::StreamJoin(proof) => {
+                let session = match &session {
+                    Some(s) => s,
+                    None => return Err(Error::Protocol(String::from("parallel stream isn't encrypted"))),
+                };
+
+                let transfer = lock(&shared.transfers)
+                    .iter()
+                    .filter(|_| agreed.has(caps::PARALLEL_STREAMS))
+                    .find(|t| t.stream_key.is_some_and(|k| crypto::join_proof(&k, &session.binding) == proof))
+                    .cloned();
+                let transfer = match transfer {
+                    Some(t) => t,
+                    None => {
+                        transport::send_slice(&mut stream, transport::Parsed::JoinRes(false).to_buf().as_ref())?;
+                        return Err(Error::Rejected(String::from(
+                            "Parallel stream doesn't belong to any request",
+                        )));
+                    }
+                };
+
+                // counted before the sender hears of it, the main connection waits for its ranges then
+                transfer.join_stream();
+                let res = transport::send_slice(&mut stream, transport::Parsed::JoinRes(true).to_buf().as_ref())
+                    .and_then(|_| receive_ranges(&transfer, &mut reader, &shared.limits));
+                transfer.leave_stream(res.is_err());
+                return Ok(res?);
             }
+            p => return Err(Error::Protocol(format!("unexpected {} outside of a request", p.name()))),
         }
     }
+}
 
-    Ok(())
+/// how often the progress of a transfer gets reported
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+/// split files are given up if the parallel streams write nothing of them for this long
+const RANGE_TIMEOUT: Duration = Duration::from_secs(60);
+/// pause after the listener failed to accept a connection
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// a poisoned lock only means another connection panicked, the data is still usable
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// turns compressed blocks into plain FILE_BLOCKs, everything after works on the raw data
+fn unpack(packet: Parsed, compression: Compression) -> io::Result<Parsed> {
+    match packet {
+        Parsed::CompressedBlock { id, size, data } => Ok(Parsed::FileBlock {
+            id,
+            data: compress::decompress(compression, &data, size as usize)?,
+        }),
+        p => Ok(p),
+    }
+}
+
+/// writes the ranges an extra stream of `transfer` sends, until the sender closes it
+fn receive_ranges<R: Read>(transfer: &Transfer, reader: &mut BufReader<R>, limits: &Limits) -> io::Result<()> {
+    // (file id, file, next offset, file size)
+    let mut current: Option<(u32, File, u64, u64)> = None;
+
+    loop {
+        match transport::parse_with(reader, limits).and_then(|p| unpack(p, transfer.compression)) {
+            Ok(Parsed::FileRange { id, offset }) => {
+                let (path, size) = transfer.files.get(&id).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "range of a file that wasn't accepted")
+                })?;
+                if offset > *size {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "range outside of the file"));
+                }
+                if !transfer.wait_split(id, RANGE_TIMEOUT) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "range of a file that isn't sent in parallel",
+                    ));
+                }
+                // FILE_SPLIT created the `.part` file
+                current = Some((id, OpenOptions::new().write(true).open(path)?, offset, *size));
+            }
+            Ok(Parsed::FileBlock { id, data }) => match &mut current {
+                Some((range_id, file, pos, size)) if *range_id == id && *pos + data.len() as u64 <= *size => {
+                    if !transfer.begin_write(id) {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, "range of a completed file"));
+                    }
+                    let res = write_at(file, &data, *pos);
+                    transfer.end_write(id, if res.is_ok() { data.len() as u64 } else { 0 });
+                    res?;
+                    *pos += data.len() as u64;
+                }
+                _ => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "block outside of a range"));
+                }
+            },
+            // sender is done
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+            Ok(p) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unexpected packet on a parallel stream: {:?}", p),
+                ))
+            }
+        }
+    }
 }
 
-pub fn recv() -> io::Result<()> {
-    //  tasks needed:
-    //  - tcp-listener : handles ping and receiving of files
-    //  - terminal-handler: ask for confirmation of receiving and handle settings
-    // communicate via channels?
-    tcp_handler()
+/// receives the accepted files of a request, `req` only contains these
+fn receive_files<R: Read, W: Write>(
+    mut req: Vec<FileMeta>,
+    transfer: &Transfer,
+    peer: &Peer,
+    reader: &mut BufReader<R>,
+    stream: &mut W,
+    shared: &Shared,
+) -> Result<()> {
+    let file_size_sum = req.iter().fold(0, |acc: u64, e| acc.saturating_add(e.size));
+    let log = |text: &str| {
+        (shared.events)(&Event::Message {
+            peer: peer.clone(),
+            text: text.to_string(),
+        })
+    };
+    let log_err = |text: &str| {
+        (shared.events)(&Event::Warning {
+            peer: peer.clone(),
+            text: text.to_string(),
+        })
+    };
+    let progress = |name: &str, file: (usize, usize), bytes: u64| {
+        (shared.events)(&Event::Progress {
+            peer: peer.clone(),
+            name: name.to_string(),
+            file,
+            bytes,
+            total: file_size_sum,
+        })
+    };
+
+    let hash_algo = transfer.algo;
+    let files_total = req.iter().filter(|e| e.kind == EntryKind::File).count();
+
+    // tell sender which files we already have parts of
+    let mut partials = Vec::new();
+    let mut resume_hashers: HashMap<u32, Hasher> = HashMap::new();
+    for fm in req.iter().filter(|e| e.kind == EntryKind::File && transfer.hello.has(caps::RESUME)) {
+        if let Some((p, hasher)) = partial_file(fm, hash_algo)? {
+            resume_hashers.insert(p.id, hasher);
+            partials.push(p);
+        }
+    }
+    transport::send_slice(stream, transport::Parsed::ResumeInfo(partials.clone()).to_buf().as_ref())?;
+
+    // directories and symlinks get created right away, even empty ones
+    for fm in req.iter().filter(|e| e.kind == EntryKind::Dir) {
+        std::fs::create_dir_all(local_path(fm)?)?;
+    }
+    for fm in req.iter().filter(|e| e.kind == EntryKind::Symlink) {
+        if let Err(e) = create_symlink(fm, &shared.dir) {
+            log_err(&format!("Can't create symlink {}: {}", fm.name, e));
+        }
+    }
+
+    // the times of directories change with every file written into them, so they come last.
+    // children are listed after their parents, deepest first keeps the parents writable
+    let dirs: Vec<FileMeta> = req.iter().filter(|e| e.kind == EntryKind::Dir).cloned().collect();
+    let finish_dirs = || {
+        for fm in dirs.iter().rev() {
+            if let Err(e) = local_path(fm).and_then(|p| apply_metadata(&p, fm)) {
+                log_err(&format!("Can't set times and permissions of {}: {}", fm.name, e));
+            }
+        }
+    };
+
+    let mut files_waiting: HashMap<u32, FileMeta> = HashMap::from_iter(
+        req.drain(..)
+            .filter(|e| e.kind == EntryKind::File)
+            .map(|e| (e.id, e)),
+    );
+
+    if files_waiting.is_empty() {
+        finish_dirs();
+        log("All files received!");
+        return Ok(());
+    }
+
+    // we need to store the current open file meta data
+    let mut current_file_meta: Option<FileMeta> = None;
+    let mut current_file_writer: Option<BufWriter<File>> = None;
+    let mut current_file_hasher = Hasher::new(hash_algo);
+    // bytes of the current file in its `.part` file, never more than announced
+    let mut current_written: u64 = 0;
+    let mut failed_files: Vec<String> = Vec::new();
+    // offset the ranges of the current file start at, if it's send over the extra streams
+    let mut split: Option<u64> = None;
+
+    let mut last_draw: Option<Instant> = None;
+    let mut bytes_recvd: u64 = 0;
+    let mut files_received = 0;
+    loop {
+        let packet = match transport::parse_with(reader, &shared.limits) {
+            Ok(p) => unpack(p, transfer.compression)?,
+            Err(e) => {
+                if let Some(w) = &mut current_file_writer {
+                    w.flush()?;
+                }
+                if let Some(fm) = &current_file_meta {
+                    if abandon(fm, split.is_some(), &shared.dir)? {
+                        log(&format!("Keeping {:?}, send again to resume", staging_path(fm)?));
+                    }
+                }
+                return Err(e.into());
+            }
+        };
+
+        match packet {
+            Parsed::FileResume { id, offset } => {
+                if let Some(fm) = &current_file_meta {
+                    abandon(fm, split.is_some(), &shared.dir)?;
+                    return Err(Error::Protocol(format!("resume of file {} while receiving file {}", id, fm.id)));
+                }
+                let partial = partials.iter().find(|p| p.id == id && p.offset == offset);
+
+                match (files_waiting.remove(&id), partial, resume_hashers.remove(&id)) {
+                    (Some(fm), Some(_), Some(hasher)) => {
+                        files_received += 1;
+                        bytes_recvd += offset;
+
+                        let mut file = OpenOptions::new().write(true).open(staging_path(&fm)?)?;
+                        file.set_len(offset)?;
+                        file.seek(SeekFrom::Start(offset))?;
+
+                        log(&format!("Resuming {} at {:.3}mb", fm.name, offset as f64 / 1_000_000.0));
+
+                        transfer.set_phase(fm.id, Phase::Main);
+                        current_file_meta = Some(fm);
+                        current_file_writer = Some(BufWriter::new(file));
+                        current_file_hasher = hasher;
+                        current_written = offset;
+                    }
+                    _ => return Err(Error::Protocol(format!("can't resume file {} at {}", id, offset))),
+                }
+            }
+            Parsed::FileBlock { id: file_id, data } => {
+                bytes_recvd += data.len() as u64;
+                current_file_hasher.update(&data);
+                match (&mut current_file_meta, &mut current_file_writer) {
+                    (Some(meta), Some(writer)) => {
+                        if meta.id != file_id {
+                            abandon(meta, false, &shared.dir)?;
+                            return Err(Error::Protocol(format!(
+                                "block of file {} while receiving file {}",
+                                file_id, meta.id
+                            )));
+                        }
+                        if current_written + data.len() as u64 > meta.size {
+                            abandon(meta, false, &shared.dir)?;
+                            return Err(Error::Protocol(format!(
+                                "{} gets longer than the {} bytes announced",
+                                meta.name, meta.size
+                            )));
+                        }
+
+                        writer.write_all(&data)?;
+                        current_written += data.len() as u64;
+                    }
+                    (Some(meta), None) => {
+                        // the ranges of a split file come over the extra streams only
+                        abandon(meta, true, &shared.dir)?;
+                        return Err(Error::Protocol(format!(
+                            "block of file {} while file {} arrives over the parallel streams",
+                            file_id, meta.id
+                        )));
+                    }
+                    (None, _) => {
+                        // create new file if want to receive
+                        if let Some(fm) = files_waiting.remove(&file_id) {
+                            files_received += 1;
+                            if data.len() as u64 > fm.size {
+                                return Err(Error::Protocol(format!(
+                                    "{} gets longer than the {} bytes announced",
+                                    fm.name, fm.size
+                                )));
+                            }
+
+                            transfer.set_phase(fm.id, Phase::Main);
+                            let mut bwriter = BufWriter::new(create_file(&staging_path(&fm)?)?);
+
+                            bwriter.write_all(&data)?;
+
+                            current_file_meta = Some(fm);
+                            current_file_writer = Some(bwriter);
+                            current_written = data.len() as u64;
+                        } else {
+                            return Err(Error::Protocol(format!(
+                                "block of file {}, it wasn't accepted or is complete already",
+                                file_id
+                            )));
+                        }
+                    }
+                }
+
+                if last_draw.is_none_or(|t| t.elapsed() > PROGRESS_INTERVAL) {
+                    last_draw = Some(Instant::now());
+                    let name = current_file_meta.as_ref().map(|fm| fm.name.as_str()).unwrap_or("");
+                    progress(name, (files_received, files_total), bytes_recvd);
+                }
+            }
+            Parsed::FileSplit { id: file_id, offset } => {
+                if let Some(fm) = &current_file_meta {
+                    abandon(fm, split.is_some(), &shared.dir)?;
+                    return Err(Error::Protocol(format!(
+                        "parallel file {} while receiving file {}",
+                        file_id, fm.id
+                    )));
+                }
+                let resumable = offset == 0 || partials.iter().any(|p| p.id == file_id && p.offset == offset);
+
+                match files_waiting.remove(&file_id) {
+                    Some(fm) if resumable && offset <= fm.size => {
+                        files_received += 1;
+                        bytes_recvd += offset;
+
+                        // the streams might have written ranges already, only fix the length
+                        open_for_ranges(&staging_path(&fm)?)?.set_len(fm.size)?;
+                        if offset > 0 {
+                            log(&format!("Resuming {} at {:.3}mb", fm.name, offset as f64 / 1_000_000.0));
+                        }
+
+                        transfer.set_phase(fm.id, Phase::Split);
+                        current_file_meta = Some(fm);
+                        split = Some(offset);
+                    }
+                    _ => {
+                        return Err(Error::Protocol(format!("can't receive file {} in parallel", file_id)));
+                    }
+                }
+            }
+            Parsed::FileEnd(digest) => {
+                let calculated = match (split.take(), &current_file_meta) {
+                    (Some(offset), Some(fm)) => {
+                        // wait until the streams wrote all ranges, then hash the whole file
+                        let missing = fm.size - offset;
+                        let mut last_change = (0, Instant::now());
+                        let written = loop {
+                            let (written, done) = transfer.wait_written(fm.id, missing, Duration::from_millis(200));
+                            progress(&fm.name, (files_received, files_total), bytes_recvd + written);
+                            if written != last_change.0 {
+                                last_change = (written, Instant::now());
+                            }
+                            if done || last_change.1.elapsed() > RANGE_TIMEOUT {
+                                break written;
+                            }
+                        };
+                        // nothing writes into the file while it's hashed and renamed
+                        transfer.finish_split(fm.id);
+                        if written < missing {
+                            abandon(fm, true, &shared.dir)?;
+                            return Err(Error::Network(io::Error::new(
+                                io::ErrorKind::UnexpectedEof,
+                                format!("the parallel streams stopped before all ranges of {} arrived", fm.name),
+                            )));
+                        }
+                        bytes_recvd += written;
+
+                        let mut hasher = Hasher::new(hash_algo);
+                        hasher.update_from(&mut BufReader::new(File::open(staging_path(fm)?)?), fm.size)?;
+                        hasher.finish()
+                    }
+                    _ => std::mem::replace(&mut current_file_hasher, Hasher::new(hash_algo)).finish(),
+                };
+                if let Some(mut w) = current_file_writer.take() {
+                    w.flush()?;
+                }
+
+                if digest != calculated {
+                    log_err(&format!(
+                        "Hash not identical! calculated: {} | received: {}",
+                        hash::to_hex(&calculated),
+                        hash::to_hex(&digest)
+                    ));
+                    // don't leave corrupted data behind
+                    if let Some(fm) = current_file_meta.take() {
+                        let path = staging_path(&fm)?;
+                        std::fs::remove_file(&path)?;
+                        log_err(&format!("Removed {:?}", path));
+                        failed_files.push(fm.name);
+                    }
+                } else if let Some(fm) = current_file_meta.take() {
+                    // only complete files get their real name
+                    let path = local_path(&fm)?;
+                    std::fs::rename(staging_path(&fm)?, &path)?;
+                    if let Err(e) = apply_metadata(&path, &fm) {
+                        log_err(&format!("Can't set times and permissions of {}: {}", fm.name, e));
+                    }
+                    (shared.events)(&Event::FileDone {
+                        peer: peer.clone(),
+                        name: fm.name,
+                        path: Some(path),
+                    });
+                }
+
+                if files_waiting.is_empty() {
+                    finish_dirs();
+                    if !failed_files.is_empty() {
+                        return Err(Error::Checksum(failed_files));
+                    }
+                    log("All files received!");
+                    return Ok(());
+                }
+            }
+            p => {
+                if let Some(fm) = &current_file_meta {
+                    abandon(fm, split.is_some(), &shared.dir)?;
+                }
+                return Err(Error::Protocol(format!("unexpected {} during the transfer", p.name())));
+            }
+        }
+    }
 }
@@ -1,211 +1,731 @@
-use crate::AppState;
+use crate::compress::{Compression, Compressor};
+use crate::conn::{ConnRead, ConnWrite, Dial, Endpoint, Peer};
+use crate::crypto::{self, SecureReader, SecureWriter};
+use crate::discovery;
+use crate::error::{Error, Result};
+use crate::event::{self, Event, Handler, Request};
+use crate::hash::{self, HashAlgo, Hasher};
+use crate::manifest::Manifest;
+use crate::transport::{self, caps, BlockBuf, BlockWrite, EntryKind, FileMeta, Hello, Parsed, PartialFile};
 
-use crate::transport::{FileMeta, Parsed};
-use std::io;
-use std::net::{SocketAddr, TcpStream};
-
-fn get_file_meta(mut files: Vec<PathBuf>) -> Vec<FileMeta> {
-    files
-        .drain(..)
-        .filter_map(|m| FileMeta::from(m).ok())
-        .collect()
-}
-
-use crate::transport;
 use std::collections::HashSet;
 use std::fs::File;
-use std::io::{BufReader, Read};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
 use std::path::PathBuf;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// sends manifests to one receiver
+pub struct Sender {
+    to: Arc<dyn Dial>,
+    streams: usize,
+    encrypt: bool,
+    compression: Compression,
+    events: Handler,
+}
 
-pub fn send(state: crate::AppState) -> io::Result<()> {
-    #[cfg(debug_assertions)]
-    println!("send::send()");
-
-    match state {
-        AppState::Send { to, files } => {
-            // check if <to> is active (ping)
-            let mut stream: TcpStream = TcpStream::connect(to)?;
-            let mut reader = BufReader::new(stream.try_clone()?);
-            transport::send_slice(&mut stream, &[transport::flags::PING])?;
-
-            // wait for ping
-            {
-                let mut answ = [0u8];
-                stream.read_exact(&mut answ)?;
-
-                if answ[0] != transport::flags::PONG {
-                    eprintln!("Receiver can't be reached. Make sure that both are connected to the same network and sfshare is running in recv mode.");
-                    return Err(io::Error::new(io::ErrorKind::NotConnected, "Not reachable"));
-                }
-            }
+impl Sender {
+    /// sends encrypted over one connection, without compression.
+    /// `to` is a `SocketAddr`, an `Endpoint` or anything else that can open connections
+    pub fn new<D: Dial + 'static>(to: D) -> Sender {
+        Sender {
+            to: Arc::new(to),
+            streams: 1,
+            encrypt: true,
+            compression: Compression::None,
+            events: event::ignore(),
+        }
+    }
 
-            // calculate file size
-            let file_meta = get_file_meta(files);
-            if file_meta.is_empty() {
-                eprintln!("No files found.");
-                return Err(io::Error::from(io::ErrorKind::NotFound));
-            }
+    /// parallel connections for large files, 1 sends everything over the main connection
+    pub fn streams(mut self, streams: usize) -> Sender {
+        self.streams = streams.max(1);
+        self
+    }
+
+    /// unencrypted connections allow sending straight from the page cache
+    pub fn encrypt(mut self, encrypt: bool) -> Sender {
+        self.encrypt = encrypt;
+        self
+    }
+
+    pub fn compression(mut self, compression: Compression) -> Sender {
+        self.compression = compression;
+        self
+    }
+
+    pub fn on_event<F: Fn(&Event) + Send + Sync + 'static>(mut self, handler: F) -> Sender {
+        self.events = Arc::new(handler);
+        self
+    }
 
-            #[cfg(debug_assertions)]
-            println!("{:?}", file_meta);
-
-            let total_size = file_meta.iter().fold(0, |acc, m| acc + m.size);
-
-            // 1 mb or too many files
-            // TODO save different limit
-            if total_size > 1_000_000 || file_meta.len() > 5 {
-                println!(
-                    "Are you sure you want to send {} files with {}mb size total?",
-                    file_meta.len(),
-                    total_size as f64 / 1_000_000f64
-                );
-
-                let mut res = String::new();
-                while !(res.trim() == "y" || res.trim() == "yes") {
-                    println!("[y] yes / [n] no");
-                    io::stdin().read_line(&mut res)?;
-                    if res.trim() == "n" || res.trim() == "no" {
-                        return Err(io::Error::from(io::ErrorKind::ConnectionAborted));
+    /// sends the entries of `manifest`, returns when all files the receiver accepted are send
+    pub fn send(&self, manifest: &Manifest) -> Result<()> {
+        let Connection {
+            mut reader,
+            writer: mut stream,
+            peer,
+            session,
+            hello,
+        } = connect(&*self.to, self.encrypt)?;
+        let events = &*self.events;
+        let mut report = Report::new(peer.clone(), events);
+        events(&Event::Connected { peer: peer.clone() });
+
+        let file_meta = fit_to_receiver(manifest.entries().to_vec(), &hello, &report)?;
+        if file_meta.is_empty() {
+            return Err(Error::Usage(String::from("No files found")));
+        }
+
+        let files_total = file_meta.iter().filter(|m| m.kind == EntryKind::File).count();
+
+        transport::send_slice(
+            &mut stream,
+            Parsed::AckReq {
+                files: file_meta.clone(),
+                hashes: hash::SUPPORTED.to_vec(),
+                compressions: match self.compression {
+                    Compression::None => Vec::new(),
+                    _ if !hello.has(caps::COMPRESSION) => {
+                        report.message(String::from(
+                            "The receiver doesn't support compression, sending files as they are",
+                        ));
+                        Vec::new()
                     }
-                }
+                    c => vec![c],
+                },
             }
-
-            // continue - establish connection
-            transport::send_slice(
-                &mut stream,
-                transport::Parsed::AckReq(file_meta.clone())
-                    .to_buf()
-                    .as_ref(),
-            )?;
-            println!("Asked receiver if he wants to receive files...\nWaiting for answer");
-            match transport::parse(&mut reader).unwrap() {
-                transport::Parsed::AckRes(ack) => {
-                    if !ack {
-                        eprintln!("The receiver didn't accept your request :( maybe next time");
-                        return Err(io::Error::from(io::ErrorKind::ConnectionRefused));
+            .to_buf()
+            .as_ref(),
+        )?;
+        events(&Event::Request(Request {
+            peer: peer.clone(),
+            files: file_meta.clone(),
+            code: session.as_ref().map(|s| s.code.clone()),
+        }));
+
+        let accepted: HashSet<u32>;
+        let (hash_algo, compression) = match transport::parse(&mut reader)? {
+            Parsed::AckRes {
+                accept,
+                hash,
+                compression,
+                reason,
+                files,
+            } => {
+                if !accept {
+                    events(&Event::Rejected {
+                        peer,
+                        reason: reason.clone(),
+                    });
+                    let mut text = String::from("The receiver didn't accept your request :( maybe next time");
+                    if !reason.is_empty() {
+                        text = format!("{}\nReason: {}", text, reason);
                     }
+                    return Err(Error::Rejected(text));
                 }
-                _ => {
-                    eprintln!("Expected AckRes");
-                    return Err(io::Error::from(io::ErrorKind::InvalidData));
+                accepted = files
+                    .iter()
+                    .filter(|(_, accept)| *accept)
+                    .map(|(id, _)| *id)
+                    .collect();
+                (hash, compression)
+            }
+            p => return Err(Error::Protocol(format!("expected ACK_RES, got {}", p.name()))),
+        };
+
+        // receiver tells us which files it already holds partially
+        let partials = match transport::parse(&mut reader)? {
+            Parsed::ResumeInfo(partials) if hello.has(caps::RESUME) => partials,
+            Parsed::ResumeInfo(_) => Vec::new(),
+            p => return Err(Error::Protocol(format!("expected RESUME_INFO, got {}", p.name()))),
+        };
+
+        events(&Event::Accepted {
+            peer,
+            accepted: accepted.len(),
+            total: files_total,
+        });
+        if compression != Compression::None {
+            report.message(format!("Compressing with {:?}", compression));
+        }
+
+        // receiver accepted request
+        let files: Vec<&FileMeta> = file_meta
+            .iter()
+            .filter(|m| m.kind == EntryKind::File && accepted.contains(&m.id))
+            .collect();
+        report.files = files.len();
+        report.total = files.iter().map(|fm| fm.size).sum();
+
+        // large files get split across extra connections
+        let parallel = match &session {
+            _ if self.streams > 1 && !hello.has(caps::PARALLEL_STREAMS) => {
+                report.warning(String::from("The receiver doesn't support parallel streams, using one"));
+                None
+            }
+            Some(s) if self.streams > 1 && files.iter().any(|fm| fm.size > RANGE_SIZE) => {
+                Streams::open(&*self.to, &s.stream_key, self.streams, &report)
+            }
+            None if self.streams > 1 => {
+                report.warning(String::from("Parallel streams need an encrypted connection, using one"));
+                None
+            }
+            _ => None,
+        };
+
+        for (i, fm) in files.iter().enumerate() {
+            let partial = partials.iter().find(|p| p.id == fm.id);
+            match &parallel {
+                Some(p) if fm.size > RANGE_SIZE => {
+                    send_split(fm, hash_algo, compression, partial, &mut stream, p, (i, &mut report))?
                 }
+                _ => send_file(fm, hash_algo, compression, partial, &mut stream, (i, &mut report))?,
             }
+        }
+        if let Some(p) = parallel {
+            p.close();
+        }
+
+        Ok(())
+    }
+}
 
-            println!("starting to send files...");
-            let start = std::time::Instant::now();
-            // receiver accepted request
-            for (i, fm) in file_meta.iter().enumerate() {
-                send_file(fm, &mut stream, (i, file_meta.len()))?;
+/// leaves out what the receiver doesn't support, fails if the request can't be send without it
+fn fit_to_receiver(mut meta: Vec<FileMeta>, hello: &Hello, report: &Report) -> Result<Vec<FileMeta>> {
+    if !hello.has(caps::DIRECTORIES) && meta.iter().any(|m| m.kind != EntryKind::File) {
+        return Err(Error::Incompatible(String::from(
+            "The receiver can't receive directories, only send files",
+        )));
+    }
+    if !hello.has(caps::METADATA) {
+        meta.retain(|m| {
+            if m.kind == EntryKind::Symlink {
+                report.warning(format!("Skipping symlink {}, the receiver doesn't support them", m.name));
             }
+            m.kind != EntryKind::Symlink
+        });
+        for m in meta.iter_mut() {
+            m.mtime = None;
+            m.mode = None;
+        }
+    }
+    Ok(meta)
+}
 
-            println!("Took {}s", start.elapsed().as_secs_f64());
+/// encrypted connection to the receiver
+struct Connection {
+    reader: BufReader<SecureReader<Box<dyn ConnRead>>>,
+    writer: SecureWriter<Box<dyn ConnWrite>>,
+    peer: Peer,
+    /// `None` if the connection isn't encrypted
+    session: Option<crypto::Session>,
+    /// protocol version and features both sides support
+    hello: Hello,
+}
+
+/// receivers answer HELLO right away, older versions don't know it and never answer
+const HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// connects to `to` and encrypts the connection if `encrypt` is set
+fn connect(to: &dyn Dial, encrypt: bool) -> Result<Connection> {
+    // check if <to> is active and speaks our protocol
+    let conn = to.dial().map_err(Error::Network)?;
+    let peer = conn.peer();
+    let (read, write) = conn.split()?;
+    read.set_read_timeout(Some(HELLO_TIMEOUT))?;
+    let mut reader = BufReader::new(SecureReader::new(read));
+    let mut stream = SecureWriter::new(write);
+    transport::send_slice(&mut stream, Parsed::Hello(Hello::ours()).to_buf().as_ref())?;
+
+    let hello = match transport::parse(&mut reader) {
+        Ok(Parsed::HelloRes(peer)) => Hello::ours().agree(&peer),
+        Ok(p) => return Err(Error::Protocol(format!("expected HELLO_RES, got {}", p.name()))),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+            return Err(Error::Incompatible(String::from(
+                "The receiver didn't answer, it might run an older sfshare version",
+            )));
         }
-        _ => return Err(std::io::Error::from(std::io::ErrorKind::Other)),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            return Err(Error::Network(io::Error::new(
+                e.kind(),
+                "the receiver closed the connection right away",
+            )));
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let hello = hello.map_err(|e| Error::Incompatible(format!("Can't talk to the receiver: {}", e)))?;
+    reader.get_ref().get_ref().set_read_timeout(None)?;
+
+    if !encrypt {
+        return Ok(Connection {
+            reader,
+            writer: stream,
+            peer,
+            session: None,
+            hello,
+        });
+    }
+    if !hello.has(caps::ENCRYPTION) {
+        return Err(Error::Incompatible(String::from(
+            "The receiver doesn't support encryption, only --no-encryption works with it",
+        )));
     }
 
-    Ok(())
+    // encrypt everything from here on. our key is only revealed after the receiver sent its own
+    let handshake = crypto::Handshake::new();
+    transport::send_slice(&mut stream, Parsed::KeyCommit(handshake.commitment()).to_buf().as_ref())?;
+    let peer_key = match transport::parse(&mut reader)? {
+        Parsed::KeyExchange(key) => key,
+        p => return Err(Error::Protocol(format!("expected KEY_EXCHANGE, got {}", p.name()))),
+    };
+    transport::send_slice(
+        &mut stream,
+        Parsed::KeyExchange(handshake.public_key()).to_buf().as_ref(),
+    )?;
+    let keys = handshake.finish(crypto::Role::Sender, peer_key)?;
+    stream.start_encryption(keys.send);
+    crypto::start_decryption(&mut reader, keys.recv)?;
+
+    Ok(Connection {
+        reader,
+        writer: stream,
+        peer,
+        session: Some(keys.session),
+        hello,
+    })
+}
+
+/// how often the progress gets reported
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// reports what happens with one request to the event handler
+struct Report<'a> {
+    peer: Peer,
+    events: &'a (dyn Fn(&Event) + Send + Sync),
+    /// accepted files
+    files: usize,
+    /// size of the accepted files
+    total: u64,
+    /// bytes of the files send completely
+    done: u64,
+    last_progress: Option<Instant>,
+}
+
+impl<'a> Report<'a> {
+    fn new(peer: Peer, events: &'a (dyn Fn(&Event) + Send + Sync)) -> Report<'a> {
+        Report {
+            peer,
+            events,
+            files: 0,
+            total: 0,
+            done: 0,
+            last_progress: None,
+        }
+    }
+
+    fn message(&self, text: String) {
+        (self.events)(&Event::Message {
+            peer: self.peer.clone(),
+            text,
+        });
+    }
+
+    fn warning(&self, text: String) {
+        (self.events)(&Event::Warning {
+            peer: self.peer.clone(),
+            text,
+        });
+    }
+
+    /// `bytes` of file `k` are send, only reported every `PROGRESS_INTERVAL` and at its end
+    fn progress(&mut self, fm: &FileMeta, k: usize, bytes: u64) {
+        if bytes < fm.size && self.last_progress.is_some_and(|t| t.elapsed() < PROGRESS_INTERVAL) {
+            return;
+        }
+        self.last_progress = Some(Instant::now());
+        (self.events)(&Event::Progress {
+            peer: self.peer.clone(),
+            name: fm.name.clone(),
+            file: (k + 1, self.files),
+            bytes: self.done + bytes,
+            total: self.total,
+        });
+    }
+
+    fn file_done(&mut self, fm: &FileMeta, algo: HashAlgo, digest: &[u8]) {
+        self.done += fm.size;
+        self.last_progress = None;
+        self.message(format!("{:?} hash of {}: {}", algo, fm.name, hash::to_hex(digest)));
+        (self.events)(&Event::FileDone {
+            peer: self.peer.clone(),
+            name: fm.name.clone(),
+            path: None,
+        });
+    }
+}
+
+/// checks if the receivers partial file matches the start of our file.
+/// returns the offset and hasher state to continue from, leaves `reader` at that offset
+fn resume_point<R: Read + Seek>(
+    reader: &mut R,
+    fm: &FileMeta,
+    algo: HashAlgo,
+    partial: Option<&PartialFile>,
+    report: &Report,
+) -> io::Result<(u64, Hasher)> {
+    let partial = match partial {
+        Some(p) if p.offset > 0 && p.offset <= fm.size => p,
+        _ => return Ok((0, Hasher::new(algo))),
+    };
+
+    let mut hasher = Hasher::new(algo);
+    hasher.update_from(reader, partial.offset)?;
+
+    if hasher.clone().finish() == partial.digest {
+        Ok((partial.offset, hasher))
+    } else {
+        report.message(format!("Partial file of receiver differs, sending {} again", fm.name));
+        reader.seek(SeekFrom::Start(0))?;
+        Ok((0, Hasher::new(algo)))
+    }
+}
+
+/// blocks start this small, so slow connections show progress right away
+const MIN_BLOCK_SIZE: usize = 16 * 1024;
+
+/// size of the next block, follows the speed of the connection.
+/// blocks grow while they get written fast and shrink again on slow links
+struct BlockSize(usize);
+
+impl BlockSize {
+    fn adapt(&mut self, took: Duration) {
+        if took < Duration::from_millis(10) {
+            self.0 = (self.0 * 2).min(transport::MAX_BLOCK_SIZE);
+        } else if took > Duration::from_millis(100) {
+            self.0 = (self.0 / 2).max(MIN_BLOCK_SIZE);
+        }
+    }
 }
 
-pub fn send_file(fm: &FileMeta, stream: &mut TcpStream, k_of_n: (usize, usize)) -> io::Result<()> {
-    use crossterm::cursor::{MoveDown, MoveUp};
-    use crossterm::style::Print;
-    use crossterm::terminal::{Clear, ClearType};
-    use crossterm::{queue};
-    use std::io::{stdout, Write};
+/// sends the next `len` bytes of `file` as FILE_BLOCKs of file `id` and hashes them with `hasher`.
+/// blocks `compressor` makes smaller get send compressed.
+/// `progress` gets the bytes send so far after every block
+pub fn send_blocks<W: BlockWrite>(
+    file: &mut File,
+    id: u32,
+    len: u64,
+    mut hasher: Option<&mut Hasher>,
+    mut compressor: Option<&mut Compressor>,
+    stream: &mut W,
+    progress: &mut dyn FnMut(u64) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut block = BlockBuf::new();
+    let mut block_size = BlockSize(MIN_BLOCK_SIZE);
+    let mut offset = file.stream_position()?;
+    let mut send = 0;
+
+    while send < len {
+        let n = (len - send).min(block_size.0 as u64) as usize;
+        let start = Instant::now();
+
+        block.read_from(id, file, n)?;
+        if let Some(h) = &mut hasher {
+            h.update(block.data());
+        }
+
+        let packed = match &mut compressor {
+            Some(c) => c.compress(block.data())?,
+            None => None,
+        };
+        match packed {
+            Some(data) => {
+                stream.write_all(&transport::compressed_header(id, n, data.len()))?;
+                stream.write_all(data)?;
+            }
+            None => stream.write_block(&block, file, offset)?,
+        }
 
+        block_size.adapt(start.elapsed());
+        offset += n as u64;
+        send += n as u64;
+        progress(send)?;
+    }
+    Ok(())
+}
+
+/// sends file number `k` of the request over `stream`
+fn send_file<W: BlockWrite>(
+    fm: &FileMeta,
+    algo: HashAlgo,
+    compression: Compression,
+    partial: Option<&PartialFile>,
+    stream: &mut W,
+    (k, report): (usize, &mut Report),
+) -> io::Result<()> {
     let path = if let Some(p) = &fm.path {
         p.clone()
     } else {
         return Err(io::Error::from(io::ErrorKind::NotFound));
     };
-    let mut reader = BufReader::new(File::open(path)?);
+    let mut file = File::open(path)?;
+
+    let (bytes_send, mut hasher) = resume_point(&mut file, fm, algo, partial, report)?;
+    let mut compressor = Compressor::new(compression.for_file(&fm.name))?;
+
+    if bytes_send > 0 {
+        report.message(format!("Resuming {} at {:.3}mb", fm.name, bytes_send as f64 / 1_000_000.0));
+        transport::send_slice(
+            stream,
+            Parsed::FileResume {
+                id: fm.id,
+                offset: bytes_send,
+            }
+            .to_buf()
+            .as_ref(),
+        )?;
+    }
 
-    let mut bytes_send = 0u64;
+    report.progress(fm, k, bytes_send);
+    if fm.size == 0 {
+        // FILE_END names no file, an empty block tells the receiver which one it ends
+        transport::send_slice(stream, &transport::block_header(fm.id, 0))?;
+    }
+    send_blocks(
+        &mut file,
+        fm.id,
+        fm.size - bytes_send,
+        Some(&mut hasher),
+        Some(&mut compressor),
+        stream,
+        &mut |send| {
+            report.progress(fm, k, bytes_send + send);
+            Ok(())
+        },
+    )?;
 
-    let mut checksum = 0u64;
+    // send FILE_END
+    let digest = hasher.finish();
+    transport::send_slice(stream, Parsed::FileEnd(digest.clone()).to_buf().as_ref())?;
+    report.file_done(fm, algo, &digest);
 
-    queue!(stdout(), MoveDown(3)).unwrap();
-    let mut blocks_till_redraw = 0;
-    loop {
-        let percent_send = bytes_send as f64 / fm.size as f64;
-        let block_size: u16 = (fm.size - bytes_send).min(1300) as u16;
+    Ok(())
+}
 
-        if blocks_till_redraw <= 0 || block_size == 0 {
-            queue!(
-                stdout(),
-                MoveUp(3),
-                Clear(ClearType::CurrentLine),
-                Print(format!(
-                    "Copying file {} | {}/{}\n",
-                    fm.name, k_of_n.0, k_of_n.1
-                )),
-                Clear(ClearType::CurrentLine),
-                Print(format!(
-                    "{:.3}mb of {:.3}mb send ({:.2}%)\n",
-                    (bytes_send as f64 / 1_000_000.0),
-                    (fm.size as f64 / 1_000_000.0),
-                    percent_send * 100.0
-                )),
-                Clear(ClearType::CurrentLine),
-                Print(format!(
-                    "[{}>{}]\n",
-                    "=".repeat((percent_send * 20.0).floor() as usize),
-                    " ".repeat(((1.0 - percent_send) * 20.0).ceil() as usize)
-                ))
-            ).expect("Failed to display loadiing bar");
-            blocks_till_redraw = 20.min(fm.size as i64 / (block_size as i64 * 10 + 1));
-        }
+/// files larger than this get split into ranges of this size if parallel streams are used
+const RANGE_SIZE: u64 = 4 * 1024 * 1024;
 
-        if block_size == 0 {
-            break;
-        }
+/// part of a file, send over one of the extra streams
+struct Range {
+    id: u32,
+    path: PathBuf,
+    offset: u64,
+    len: u64,
+    compression: Compression,
+}
 
-        let mut data = vec![0u8; block_size as usize];
+fn send_range<W: BlockWrite>(range: &Range, stream: &mut W) -> io::Result<()> {
+    let mut file = File::open(&range.path)?;
+    file.seek(SeekFrom::Start(range.offset))?;
 
-        reader.read_exact(&mut data)?;
+    transport::send_slice(
+        stream,
+        Parsed::FileRange {
+            id: range.id,
+            offset: range.offset,
+        }
+        .to_buf()
+        .as_ref(),
+    )?;
 
-        checksum =
-            (checksum + data.iter().fold(0u64, |acc, b| acc + *b as u64)) % transport::CHECKSUM_MOD;
+    let mut compressor = Compressor::new(range.compression)?;
+    send_blocks(&mut file, range.id, range.len, None, Some(&mut compressor), stream, &mut |_| Ok(()))?;
+    stream.flush()
+}
 
-        let packet = Parsed::FileBlock { id: fm.id, data };
+/// opens an extra connection and adds it to the session of `stream_key`.
+/// returns `None` if the receiver refuses it
+fn open_stream(to: &dyn Dial, stream_key: &[u8; 32]) -> Result<Option<SecureWriter<Box<dyn ConnWrite>>>> {
+    let mut con = connect(to, true)?;
+    let binding = con.session.as_ref().map(|s| s.binding).unwrap_or_default();
+    let proof = crypto::join_proof(stream_key, &binding);
+    transport::send_slice(&mut con.writer, Parsed::StreamJoin(proof).to_buf().as_ref())?;
+
+    match transport::parse(&mut con.reader)? {
+        Parsed::JoinRes(true) => Ok(Some(con.writer)),
+        _ => Ok(None),
+    }
+}
 
-        stream.write_all(packet.to_buf().as_ref())?;
+/// extra connections of a parallel transfer, every one sends the ranges it takes from `jobs`
+struct Streams {
+    jobs: mpsc::Sender<Range>,
+    /// bytes of every finished range
+    done: mpsc::Receiver<io::Result<u64>>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
 
-        bytes_send += block_size as u64;
-        blocks_till_redraw -= 1;
-    }
+impl Streams {
+    /// opens up to `count` streams, `None` if the receiver accepts none
+    fn open(to: &dyn Dial, stream_key: &[u8; 32], count: usize, report: &Report) -> Option<Streams> {
+        let (jobs, job_rx) = mpsc::channel::<Range>();
+        let job_rx = Arc::new(Mutex::new(job_rx));
+        let (done_tx, done) = mpsc::channel();
+        let mut workers = Vec::with_capacity(count);
+
+        for i in 0..count {
+            let mut stream = match open_stream(to, stream_key) {
+                Ok(Some(s)) => s,
+                Ok(None) => {
+                    report.warning(String::from("The receiver doesn't support parallel streams"));
+                    break;
+                }
+                Err(e) => {
+                    report.warning(format!("Can't open parallel stream {}: {}", i + 1, e));
+                    break;
+                }
+            };
+
+            let job_rx = Arc::clone(&job_rx);
+            let done_tx = done_tx.clone();
+            workers.push(thread::spawn(move || loop {
+                let range = match job_rx.lock().unwrap_or_else(|e| e.into_inner()).recv() {
+                    Ok(r) => r,
+                    // all ranges are send
+                    Err(_) => break,
+                };
+                let res = send_range(&range, &mut stream).map(|_| range.len);
+                let failed = res.is_err();
+                if done_tx.send(res).is_err() || failed {
+                    break;
+                }
+            }));
+        }
 
-    // send FILE_END
-    println!("checksum for file: {}", checksum);
-    transport::send_slice(stream, Parsed::FileEnd(checksum).to_buf().as_ref())?;
+        if workers.is_empty() {
+            return None;
+        }
+        report.message(format!("Sending large files over {} parallel connections", workers.len()));
+        Some(Streams { jobs, done, workers })
+    }
 
-    Ok(())
+    /// waits until every stream sent its last range
+    fn close(self) {
+        drop(self.jobs);
+        for w in self.workers {
+            let _ = w.join();
+        }
+    }
 }
 
-pub fn match_send(args: &Vec<String>) -> io::Result<crate::AppState> {
-    // TODO customize port
-    if args.len() < 4 {
-        eprintln!("Specify at least one file");
-        return Err(io::Error::from(io::ErrorKind::InvalidInput));
+/// sends `fm` as ranges over the extra `streams`, `stream` only gets FILE_SPLIT and FILE_END.
+/// the receiver checks the hash of the whole file after all ranges arrived
+fn send_split<W: Write>(
+    fm: &FileMeta,
+    algo: HashAlgo,
+    compression: Compression,
+    partial: Option<&PartialFile>,
+    stream: &mut W,
+    streams: &Streams,
+    (k, report): (usize, &mut Report),
+) -> io::Result<()> {
+    let path = fm.path.clone().ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+    let mut reader = BufReader::new(File::open(&path)?);
+
+    let (offset, _) = resume_point(&mut reader, fm, algo, partial, report)?;
+    if offset > 0 {
+        report.message(format!("Resuming {} at {:.3}mb", fm.name, offset as f64 / 1_000_000.0));
+    }
+    transport::send_slice(stream, Parsed::FileSplit { id: fm.id, offset }.to_buf().as_ref())?;
+
+    let mut ranges = 0;
+    let mut start = offset;
+    while start < fm.size {
+        let len = RANGE_SIZE.min(fm.size - start);
+        let range = Range {
+            id: fm.id,
+            path: path.clone(),
+            offset: start,
+            len,
+            compression: compression.for_file(&fm.name),
+        };
+        streams
+            .jobs
+            .send(range)
+            .map_err(|_| io::Error::other("parallel streams closed"))?;
+        ranges += 1;
+        start += len;
+    }
+
+    // hash the whole file while the streams send it
+    reader.seek(SeekFrom::Start(0))?;
+    let mut hasher = Hasher::new(algo);
+    hasher.update_from(&mut reader, fm.size)?;
+
+    let mut bytes_send = offset;
+    report.progress(fm, k, bytes_send);
+    for _ in 0..ranges {
+        match streams.done.recv() {
+            Ok(len) => bytes_send += len?,
+            Err(_) => return Err(io::Error::other("all parallel streams closed")),
+        }
+        report.progress(fm, k, bytes_send);
     }
 
-    let recv: std::net::IpAddr = args[2]
-        .parse()
-        .map_err(|_| io::Error::from(io::ErrorKind::AddrNotAvailable))?;
+    let digest = hasher.finish();
+    transport::send_slice(stream, Parsed::FileEnd(digest.clone()).to_buf().as_ref())?;
+    report.file_done(fm, algo, &digest);
 
-    let mut files_to_send: HashSet<PathBuf> = HashSet::with_capacity(args.len() - 3);
+    Ok(())
+}
 
-    for pattern in &args[3..] {
-        for entry in glob::glob(pattern).expect("Pattern matching failed :/") {
-            match entry {
-                Ok(pbuf) => { files_to_send.insert(pbuf); },
-                Err(e) => eprintln!("{}", e),
+/// looks for a receiver announcing `name` in the local network
+fn find_by_name(name: &str, events: &dyn Fn(&Event)) -> Result<SocketAddr> {
+    let receivers = discovery::find_receivers(
+        discovery::DISCOVERY_PORT,
+        std::time::Duration::from_secs(1),
+        events,
+    )?;
+
+    match receivers.iter().find(|r| r.name.eq_ignore_ascii_case(name)) {
+        Some(r) => Ok(r.addr),
+        None => {
+            let mut text = format!("No receiver named {} found.", name);
+            for r in &receivers {
+                text += &format!("\n\t{:30} | {}", r.name, r.addr);
             }
+            Err(Error::Usage(text))
         }
     }
+}
+
+/// accepts `ip`, `ip:port`, `[ipv6]:port`, `host`, `host:port`, the device name of a receiver
+/// or `unix:<path>` of a unix domain socket. looking for device names reports to `events`
+pub fn resolve_target(target: &str, events: &dyn Fn(&Event)) -> Result<Endpoint> {
+    if let Some(path) = target.strip_prefix("unix:") {
+        return Ok(Endpoint::Unix(PathBuf::from(path)));
+    }
+    resolve_addr(target, events).map(Endpoint::Tcp)
+}
 
-    Ok(AppState::Send {
-        to: SocketAddr::new(recv, 5123),
-        files: files_to_send.drain().collect()
-    })
+fn resolve_addr(target: &str, events: &dyn Fn(&Event)) -> Result<SocketAddr> {
+    if let Ok(addr) = target.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    if let Ok(ip) = target.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, transport::DEFAULT_PORT));
+    }
+
+    let resolved = if target.contains(':') {
+        target.to_socket_addrs()
+    } else {
+        (target, transport::DEFAULT_PORT).to_socket_addrs()
+    };
+
+    match resolved.map(|mut addrs| addrs.next()) {
+        Ok(Some(addr)) => Ok(addr),
+        _ => find_by_name(target, events),
+    }
 }
@@ -0,0 +1,334 @@
+//! Terminal side of the cli: prints the events of senders and receivers and asks the user.
+
+use crate::progress::Progress;
+
+use sfshare::transport::{EntryKind, FileMeta};
+use sfshare::{Endpoint, Event, Manifest, Peer, Request};
+
+use crossterm::style::{style, Stylize};
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+/// prints the addresses senders can use to reach a listener on `bind`
+fn print_addresses(bind: IpAddr, port: u16) -> io::Result<()> {
+    println!("{}", "Ip Adresses to connect to".black().on_green());
+
+    if !bind.is_unspecified() {
+        println!(" > {}", SocketAddr::new(bind, port));
+        return Ok(());
+    }
+
+    let mut interfaces = if_addrs::get_if_addrs()?;
+    interfaces.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut last_name: Option<&str> = None;
+    for interface in interfaces.iter().filter(|i| !i.is_loopback()) {
+        // ipv4 listener can't be reached on ipv6 addresses
+        if let (IpAddr::V6(_), IpAddr::V4(_)) = (interface.ip(), bind) {
+            continue;
+        }
+
+        if last_name != Some(&interface.name) {
+            println!("{}", interface.name);
+            last_name = Some(&interface.name);
+        }
+
+        match interface.ip() {
+            IpAddr::V4(addr) => println!(" IPv4 > {}:{}", addr, port),
+            IpAddr::V6(addr) => println!(" IPv6 > [{}]:{}", addr, port),
+        }
+    }
+
+    Ok(())
+}
+
+/// lets the user deselect files of the request, returns the ids of the remaining files
+fn select_files(req: &[FileMeta], input: &mut dyn BufRead, out: &mut dyn Write) -> io::Result<HashSet<u32>> {
+    let files: Vec<&FileMeta> = req.iter().filter(|e| e.kind == EntryKind::File).collect();
+    let mut selected = vec![true; files.len()];
+
+    loop {
+        for (i, fm) in files.iter().enumerate() {
+            writeln!(
+                out,
+                "[{}] {:3} {} ({:.3}mb)",
+                if selected[i] { "x" } else { " " },
+                i + 1,
+                fm.name,
+                fm.size as f64 / 1_000_000.0
+            )?;
+        }
+        writeln!(out, "Enter numbers to toggle files (e.g. 2 4-6), empty line when done")?;
+
+        let mut l = String::new();
+        if input.read_line(&mut l)? == 0 || l.trim().is_empty() {
+            break;
+        }
+
+        for part in l.split_whitespace() {
+            let range = match part.split_once('-') {
+                Some((from, to)) => from.parse::<usize>().ok().zip(to.parse::<usize>().ok()),
+                None => part.parse::<usize>().ok().map(|n| (n, n)),
+            };
+            match range {
+                Some((from, to)) if from >= 1 && from <= to && to <= files.len() => {
+                    for s in &mut selected[from - 1..to] {
+                        *s = !*s;
+                    }
+                }
+                _ => writeln!(out, "Invalid selection: {}", part)?,
+            }
+        }
+    }
+
+    Ok(files
+        .iter()
+        .zip(selected)
+        .filter(|(_, s)| *s)
+        .map(|(fm, _)| fm.id)
+        .collect())
+}
+
+/// asks the user until they answer.
+/// returns the ids of the accepted files, `None` if the request is denied
+fn ask_accept(req: &[FileMeta], input: &mut dyn BufRead, out: &mut dyn Write) -> io::Result<Option<HashSet<u32>>> {
+    loop {
+        writeln!(out, "[y, yes] / [n, no] / [s, select files]")?;
+        let mut l = String::new();
+        if input.read_line(&mut l)? == 0 {
+            // stdin closed, nobody can answer
+            return Ok(None);
+        }
+        match l.trim() {
+            "y" | "yes" => {
+                return Ok(Some(
+                    req.iter()
+                        .filter(|e| e.kind == EntryKind::File)
+                        .map(|e| e.id)
+                        .collect(),
+                ))
+            }
+            "n" | "no" => return Ok(None),
+            "s" | "select" => {
+                let selected = select_files(req, input, out)?;
+                return Ok(if selected.is_empty() { None } else { Some(selected) });
+            }
+            _ => {}
+        }
+    }
+}
+
+/// the progress line of one transfer
+fn progress_line(peer: &Peer, name: &str, k_of_n: (usize, usize), bytes: u64, total: u64) -> String {
+    let percent_send = bytes as f64 / total.max(1) as f64;
+    format!(
+        "{} | {} {}/{} | {:.3}mb of {:.3}mb ({:.2}%) [{}>{}]",
+        peer,
+        name,
+        k_of_n.0,
+        k_of_n.1,
+        (bytes as f64 / 1_000_000.0),
+        (total as f64 / 1_000_000.0),
+        percent_send * 100.0,
+        "=".repeat((percent_send * 20.0).floor() as usize),
+        " ".repeat(((1.0 - percent_send) * 20.0).ceil() as usize)
+    )
+}
+
+
+/// the banner of a new request, with the verification code to compare
+fn request_banner(req: &Request) -> String {
+    let files_total = req.files.iter().filter(|e| e.kind == EntryKind::File).count();
+    let dirs_total = req.files.len() - files_total;
+    let size = req.files.iter().fold(0, |acc: u64, e| acc.saturating_add(e.size));
+
+    let code = match &req.code {
+        Some(code) => format!(
+            "Verification code: {} (only accept if the sender shows the same code)",
+            style(code).black().on_yellow()
+        ),
+        None => format!("{}", "The connection is not encrypted!".black().on_yellow()),
+    };
+    format!(
+        "{}\n\n{} wants to send {} file{}{} with a total size of {}mb\n{}",
+        "New Transmission Request".yellow().on_dark_magenta(),
+        req.peer,
+        files_total,
+        if files_total != 1 { "s" } else { "" },
+        if dirs_total > 0 { format!(" in {} folders", dirs_total) } else { String::new() },
+        size as f64 / 1_000_000f64,
+        code
+    )
+}
+
+/// shows what the receiver does, every transfer gets a line on the `progress` board
+pub fn recv_events(progress: Arc<Progress>) -> impl Fn(&Event) + Send + Sync {
+    move |event| match event {
+        Event::Listening { addr: Endpoint::Tcp(addr) } => {
+            if let Err(e) = print_addresses(addr.ip(), addr.port()) {
+                eprintln!("Can't list the network interfaces: {}", e);
+            }
+            println!("Waiting for files...");
+        }
+        Event::Listening { addr } => progress.message(&format!("Waiting for files on {}...", addr)),
+        Event::Connected { peer } => progress.message(&format!("{}: new connection", peer)),
+        Event::Request(req) => progress.message(&request_banner(req)),
+        Event::Accepted { peer, accepted, total } => {
+            progress.message(&format!("{}: Accepted {} of {} files", peer, accepted, total))
+        }
+        Event::Rejected { peer, reason } if !reason.is_empty() => {
+            progress.message(&format!("{}: Rejected request: {}", peer, reason))
+        }
+        Event::Rejected { .. } => {}
+        Event::Progress {
+            peer,
+            name,
+            file,
+            bytes,
+            total,
+        } => progress.update(peer, progress_line(peer, name, *file, *bytes, *total)),
+        Event::FileDone { peer, name, path } => match path {
+            Some(path) => progress.message(&format!("{}: Received {} as {:?}, hash identical", peer, name, path)),
+            None => progress.message(&format!("{}: Received {}, hash identical", peer, name)),
+        },
+        Event::Message { peer, text } => progress.message(&format!("{}: {}", peer, text)),
+        Event::Warning { peer, text } => progress.error(&format!("{}: {}", peer, text)),
+        Event::Error { peer: Some(peer), error } => progress.error(&format!("{}: {}", peer, error)),
+        Event::Error { peer: None, error } => progress.error(&format!("Can't accept connection: {}", error)),
+        Event::Closed { peer } => progress.finish(peer),
+        Event::Discovery { text } => progress.error(text),
+    }
+}
+
+/// asks the user about requests the policy leaves open, the progress lines are hidden meanwhile
+pub fn ask(progress: Arc<Progress>) -> impl Fn(&Request) -> Option<HashSet<u32>> + Send + Sync {
+    move |req| answer(&progress, req, &mut io::stdin().lock(), &mut io::stdout())
+}
+
+/// like `ask` on the terminal `tty`, for receivers whose stdin/stdout carry the connection
+pub fn ask_tty(progress: Arc<Progress>, tty: File) -> impl Fn(&Request) -> Option<HashSet<u32>> + Send + Sync {
+    move |req| answer(&progress, req, &mut BufReader::new(&tty), &mut &tty)
+}
+
+fn answer(progress: &Progress, req: &Request, input: &mut dyn BufRead, out: &mut dyn Write) -> Option<HashSet<u32>> {
+    progress.pause();
+    let answer = match ask_accept(&req.files, input, out) {
+        Ok(Some(accepted)) => Some(accepted),
+        Ok(None) => {
+            let _ = writeln!(out, "You denied the request. Listening for new requests.");
+            None
+        }
+        Err(e) => {
+            eprintln!("Can't read the answer: {}", e);
+            None
+        }
+    };
+    progress.resume();
+    answer
+}
+
+/// redraws the 3 progress lines of the sender above the cursor
+fn draw_progress(name: &str, k_of_n: (usize, usize), bytes_send: u64, total: u64) -> io::Result<()> {
+    use crossterm::cursor::MoveUp;
+    use crossterm::queue;
+    use crossterm::style::Print;
+    use crossterm::terminal::{Clear, ClearType};
+    use std::io::stdout;
+
+    let percent_send = bytes_send as f64 / total.max(1) as f64;
+    queue!(
+        stdout(),
+        MoveUp(3),
+        Clear(ClearType::CurrentLine),
+        Print(format!("Copying file {} | {}/{}\n", name, k_of_n.0, k_of_n.1)),
+        Clear(ClearType::CurrentLine),
+        Print(format!(
+            "{:.3}mb of {:.3}mb send ({:.2}%)\n",
+            (bytes_send as f64 / 1_000_000.0),
+            (total as f64 / 1_000_000.0),
+            percent_send * 100.0
+        )),
+        Clear(ClearType::CurrentLine),
+        Print(format!(
+            "[{}>{}]\n",
+            "=".repeat((percent_send * 20.0).floor() as usize),
+            " ".repeat(((1.0 - percent_send) * 20.0).ceil() as usize)
+        ))
+    )?;
+    stdout().flush()
+}
+
+/// makes room for the progress lines of the next file
+fn start_progress() -> io::Result<()> {
+    crossterm::queue!(std::io::stdout(), crossterm::cursor::MoveDown(3))
+}
+
+/// shows what the sender does
+pub fn send_events() -> impl Fn(&Event) + Send + Sync {
+    // the file the progress lines belong to
+    let current = Mutex::new(None);
+
+    move |event| match event {
+        Event::Request(req) => {
+            println!("Asked receiver if he wants to receive files...");
+            match &req.code {
+                Some(code) => println!("Verification code: {} (the receiver should see the same code)", code),
+                None => println!("The connection is not encrypted!"),
+            }
+            println!("Waiting for answer");
+        }
+        Event::Accepted { accepted, total, .. } => {
+            if accepted < total {
+                println!("The receiver declined {} of {} files", total - accepted, total);
+            }
+            println!("starting to send files...");
+        }
+        Event::Progress {
+            name,
+            file,
+            bytes,
+            total,
+            ..
+        } => {
+            let mut current = current.lock().unwrap_or_else(|e| e.into_inner());
+            if *current != Some(file.0) {
+                *current = Some(file.0);
+                let _ = start_progress();
+            }
+            let _ = draw_progress(name, *file, *bytes, *total);
+        }
+        Event::Message { text, .. } => println!("{}", text),
+        Event::Warning { text, .. } | Event::Discovery { text } => eprintln!("{}", text),
+        _ => {}
+    }
+}
+
+/// asks before sending more than 1 mb or more than 5 files, `false` if the user doesn't want to
+pub fn confirm_send(manifest: &Manifest) -> io::Result<bool> {
+    // TODO save different limit
+    if manifest.total_size() <= 1_000_000 && manifest.file_count() <= 5 {
+        return Ok(true);
+    }
+    println!(
+        "Are you sure you want to send {} files with {}mb size total?",
+        manifest.file_count(),
+        manifest.total_size() as f64 / 1_000_000f64
+    );
+
+    loop {
+        println!("[y] yes / [n] no");
+        let mut res = String::new();
+        if io::stdin().read_line(&mut res)? == 0 {
+            return Ok(false);
+        }
+        match res.trim() {
+            "y" | "yes" => return Ok(true),
+            "n" | "no" => return Ok(false),
+            _ => {}
+        }
+    }
+}
@@ -1,38 +1,115 @@
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EntryKind {
+    File,
+    Dir,
+    /// the target is in `FileMeta::link`
+    Symlink,
+}
+
+impl EntryKind {
+    fn to_byte(self) -> u8 {
+        match self {
+            EntryKind::File => 0,
+            EntryKind::Dir => 1,
+            EntryKind::Symlink => 2,
+        }
+    }
+
+    fn from_byte(b: u8) -> io::Result<EntryKind> {
+        match b {
+            0 => Ok(EntryKind::File),
+            1 => Ok(EntryKind::Dir),
+            2 => Ok(EntryKind::Symlink),
+            _ => Err(io::Error::new(ErrorKind::InvalidData, "unknown entry kind")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct FileMeta {
     pub size: u64,
     pub id: u32,
+    /// path relative to the transmitted root, components separated by `/`
     pub name: String,
+    pub kind: EntryKind,
     pub path: Option<PathBuf>,
+    /// modification time
+    pub mtime: Option<SystemTime>,
+    /// unix permission bits
+    pub mode: Option<u32>,
+    /// target of a symlink
+    pub link: Option<String>,
+}
+
+/// tags of the optional fields at the end of an entry
+mod fields {
+    pub const MTIME: u8 = 1;
+    pub const MODE: u8 = 2;
+    pub const LINK: u8 = 3;
 }
 
 impl FileMeta {
+    /// follows symlinks, the user named the path
     pub fn from(path: PathBuf) -> io::Result<FileMeta> {
-        let meta = std::fs::metadata(&path)?;
-
-        let mut file_name = path
+        let file_name = path
             .file_name()
             .and_then(|os| os.to_os_string().into_string().ok())
-            .unwrap_or(String::new());
-        file_name.truncate(std::u16::MAX as usize);
-
-        let file_id: u32 = {
-            use std::collections::hash_map::DefaultHasher;
-            use std::hash::{Hash, Hasher};
-            let mut dh = DefaultHasher::new();
-            file_name.hash(&mut dh);
-            (dh.finish() % std::u32::MAX as u64) as u32
+            .unwrap_or_default();
+
+        FileMeta::with_name(path, file_name, true)
+    }
+
+    /// `name` is the relative path the receiver stores the entry under.
+    /// symlinks become `Symlink` entries unless `follow_links` is set.
+    /// the id is 0, the sender numbers all entries of a request with `assign_ids`
+    pub fn with_name(path: PathBuf, mut file_name: String, follow_links: bool) -> io::Result<FileMeta> {
+        let meta = if follow_links {
+            std::fs::metadata(&path)?
+        } else {
+            std::fs::symlink_metadata(&path)?
+        };
+
+        // the name length has 2 bytes, cut it at a char boundary
+        let mut end = file_name.len().min(u16::MAX as usize);
+        while !file_name.is_char_boundary(end) {
+            end -= 1;
+        }
+        file_name.truncate(end);
+
+        let (kind, link) = if meta.is_symlink() {
+            let target = std::fs::read_link(&path)?
+                .into_os_string()
+                .into_string()
+                .map_err(|_| io::Error::new(ErrorKind::InvalidData, "symlink target is not valid utf8"))?;
+            (EntryKind::Symlink, Some(target))
+        } else if meta.is_dir() {
+            (EntryKind::Dir, None)
+        } else {
+            (EntryKind::File, None)
+        };
+
+        #[cfg(unix)]
+        let mode = {
+            use std::os::unix::fs::PermissionsExt;
+            Some(meta.permissions().mode() & 0o7777)
         };
+        #[cfg(not(unix))]
+        let mode = None;
 
         Ok(FileMeta {
-            size: meta.len(),
+            size: if kind == EntryKind::File { meta.len() } else { 0 },
             path: Some(path),
-            id: file_id,
+            id: 0,
             name: file_name,
+            kind,
+            mtime: meta.modified().ok(),
+            mode,
+            link,
         })
     }
 
-    pub fn from_byte_stream<T: std::io::Read>(buf: &mut BufReader<T>) -> io::Result<FileMeta> {
+    /// names longer than `limits.max_name_len` are an error, nothing of them gets read
+    pub fn from_byte_stream<T: std::io::Read>(buf: &mut BufReader<T>, limits: &Limits) -> io::Result<FileMeta> {
         let f_id = {
             let mut b = [0u8; 4];
             buf.read_exact(&mut b)?;
@@ -45,11 +122,23 @@ impl FileMeta {
             u64::from_be_bytes(b)
         };
 
+        let f_kind = {
+            let mut b = [0u8];
+            buf.read_exact(&mut b)?;
+            EntryKind::from_byte(b[0])?
+        };
+
         let f_name_len = {
             let mut b = [0u8; 2];
             buf.read_exact(&mut b)?;
             u16::from_be_bytes(b)
         };
+        if f_name_len as usize > limits.max_name_len {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("name of {} bytes, at most {} are allowed", f_name_len, limits.max_name_len),
+            ));
+        }
 
         let f_name = {
             let mut collect = vec![0u8; f_name_len as usize];
@@ -57,97 +146,483 @@ impl FileMeta {
             String::from_utf8(collect).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?
         };
 
-        Ok(FileMeta {
+        let mut fm = FileMeta {
             size: f_size,
             id: f_id,
             name: f_name,
+            kind: f_kind,
             path: None,
-        })
+            mtime: None,
+            mode: None,
+            link: None,
+        };
+
+        let fields_len = {
+            let mut b = [0u8; 2];
+            buf.read_exact(&mut b)?;
+            u16::from_be_bytes(b)
+        };
+        let mut fields = vec![0u8; fields_len as usize];
+        buf.read_exact(&mut fields)?;
+        fm.parse_fields(&fields)?;
+
+        if fm.kind == EntryKind::Symlink && fm.link.is_none() {
+            return Err(io::Error::new(ErrorKind::InvalidData, "symlink without target"));
+        }
+        Ok(fm)
+    }
+
+    /// reads the `[1 byte tag][2 byte len][data]` fields, unknown tags get skipped
+    fn parse_fields(&mut self, mut fields: &[u8]) -> io::Result<()> {
+        let invalid = |text: &str| io::Error::new(ErrorKind::InvalidData, format!("entry field: {}", text));
+
+        while !fields.is_empty() {
+            if fields.len() < 3 {
+                return Err(invalid("truncated header"));
+            }
+            let tag = fields[0];
+            let len = u16::from_be_bytes([fields[1], fields[2]]) as usize;
+            let data = fields.get(3..3 + len).ok_or_else(|| invalid("truncated data"))?;
+            fields = &fields[3 + len..];
+
+            match tag {
+                fields::MTIME => {
+                    if len != 12 {
+                        return Err(invalid("wrong mtime length"));
+                    }
+                    let secs = u64::from_be_bytes(data[..8].try_into().unwrap_or_default());
+                    let nanos = u32::from_be_bytes(data[8..].try_into().unwrap_or_default());
+                    // times that don't fit get dropped, they're only metadata
+                    self.mtime = UNIX_EPOCH.checked_add(Duration::new(secs, nanos.min(999_999_999)));
+                }
+                fields::MODE => {
+                    if len != 4 {
+                        return Err(invalid("wrong mode length"));
+                    }
+                    self.mode = Some(u32::from_be_bytes(data.try_into().unwrap_or_default()));
+                }
+                fields::LINK => {
+                    let target = String::from_utf8(data.to_vec()).map_err(|_| invalid("link is not utf8"))?;
+                    self.link = Some(target);
+                }
+                _ => {}
+            }
+        }
+        Ok(())
     }
 
     pub fn to_byte_stream(&self) -> Vec<u8> {
-        // structure : [4 byte file-id][8 byte file-size][2 byte name_len][name utf8]
+        // structure : [4 byte file-id][8 byte file-size][1 byte kind][2 byte name_len][name utf8]
+        //             [2 byte fields_len][fields]
 
         // limit file_name length to 2^16
         let file_name_bytes = self.name.as_bytes();
 
-        assert!(file_name_bytes.len() <= std::u16::MAX as usize);
+        debug_assert!(file_name_bytes.len() <= u16::MAX as usize);
 
-        let byte_size = 4 + 8 + 2 + file_name_bytes.len();
+        let mut fields = Vec::new();
+        let mut field = |tag: u8, data: &[u8]| {
+            fields.push(tag);
+            fields.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            fields.extend_from_slice(data);
+        };
+        // times before 1970 don't get send
+        if let Some(since_epoch) = self.mtime.and_then(|t| t.duration_since(UNIX_EPOCH).ok()) {
+            let mut data = since_epoch.as_secs().to_be_bytes().to_vec();
+            data.extend_from_slice(&since_epoch.subsec_nanos().to_be_bytes());
+            field(fields::MTIME, &data);
+        }
+        if let Some(mode) = self.mode {
+            field(fields::MODE, &mode.to_be_bytes());
+        }
+        if let Some(link) = &self.link {
+            field(fields::LINK, link.as_bytes());
+        }
+
+        let byte_size = 4 + 8 + 1 + 2 + file_name_bytes.len() + 2 + fields.len();
         let mut res = Vec::with_capacity(byte_size);
 
         res.extend_from_slice(&self.id.to_be_bytes());
         res.extend_from_slice(&self.size.to_be_bytes());
+        res.push(self.kind.to_byte());
         res.extend_from_slice(&(file_name_bytes.len() as u16).to_be_bytes());
         res.extend_from_slice(file_name_bytes);
-        assert_eq!(byte_size, res.len());
+        res.extend_from_slice(&(fields.len() as u16).to_be_bytes());
+        res.extend_from_slice(&fields);
+        debug_assert_eq!(byte_size, res.len());
 
         res
     }
 }
 
+/// numbers the entries of a request, ids are only unique within one request
+pub fn assign_ids(entries: &mut [FileMeta]) {
+    for (i, fm) in entries.iter_mut().enumerate() {
+        fm.id = i as u32;
+    }
+}
+
+/// the first id used by more than one entry
+pub fn duplicate_id(entries: &[FileMeta]) -> Option<u32> {
+    let mut seen = std::collections::HashSet::with_capacity(entries.len());
+    entries.iter().map(|e| e.id).find(|id| !seen.insert(*id))
+}
+
 #[test]
 fn test_bytestream() -> io::Result<()> {
-    let fm = FileMeta::from("test.dat".into())?;
+    let path = std::env::temp_dir().join(format!("sfshare_test_{}.dat", std::process::id()));
+    std::fs::write(&path, b"test data")?;
+    let fm = FileMeta::from(path.clone());
+    std::fs::remove_file(&path)?;
+    let fm = fm?;
 
     let bs = fm.to_byte_stream();
-
-    println!("{:?}", bs);
-    let reconstruct = FileMeta::from_byte_stream(&mut BufReader::new(&bs[..]))?;
+    let reconstruct = FileMeta::from_byte_stream(&mut BufReader::new(&bs[..]), &Limits::default())?;
 
     assert_eq!(fm.id, reconstruct.id);
     assert_eq!(fm.name, reconstruct.name);
     assert_eq!(fm.size, reconstruct.size);
+    assert_eq!(fm.kind, reconstruct.kind);
+    Ok(())
+}
+
+#[test]
+fn test_metadata_fields() -> io::Result<()> {
+    let fm = FileMeta {
+        size: 9,
+        id: 0,
+        name: String::from("link"),
+        kind: EntryKind::Symlink,
+        path: None,
+        mtime: Some(std::time::UNIX_EPOCH + std::time::Duration::new(1_600_000_000, 123)),
+        mode: Some(0o755),
+        link: Some(String::from("../a")),
+    };
+    let mut bs = fm.to_byte_stream();
+    assert_eq!(FileMeta::from_byte_stream(&mut BufReader::new(&bs[..]), &Limits::default())?, fm);
+
+    // fields of newer versions get skipped
+    let fields_at = 4 + 8 + 1 + 2 + fm.name.len();
+    let fields_len = u16::from_be_bytes([bs[fields_at], bs[fields_at + 1]]);
+    bs[fields_at..fields_at + 2].copy_from_slice(&(fields_len + 5).to_be_bytes());
+    bs.extend_from_slice(&[0xff, 0, 2, 1, 2]);
+    let newer = FileMeta::from_byte_stream(&mut BufReader::new(&bs[..]), &Limits::default())?;
+    assert_eq!(newer, fm);
     Ok(())
 }
 
-/**
-*/
+#[test]
+fn test_duplicate_ids() {
+    let fm = |name: &str| FileMeta {
+        size: 0,
+        id: 0,
+        name: String::from(name),
+        kind: EntryKind::File,
+        path: None,
+        mtime: None,
+        mode: None,
+        link: None,
+    };
+    // files with the same name from different folders
+    let mut entries = vec![fm("a/x"), fm("b/x")];
+    assert_eq!(duplicate_id(&entries), Some(0));
+    assign_ids(&mut entries);
+    assert_eq!(duplicate_id(&entries), None);
+}
+
+pub const DEFAULT_PORT: u16 = 5123;
+
+/// largest FILE_BLOCK either side sends or accepts
+pub const MAX_BLOCK_SIZE: usize = 4 * 1024 * 1024;
+/// `[1 byte flag][4 byte file id][4 byte block size]`
+pub const BLOCK_HEADER_SIZE: usize = 9;
+
 pub mod flags {
+    /// handshake of version 1, only used to recognize old senders
     pub const PING: u8 = 0x01;
-    pub const PONG: u8 = 0x02;
+    pub const KEY_EXCHANGE: u8 = 0x03;
+    pub const HELLO: u8 = 0x04;
+    pub const HELLO_RES: u8 = 0x05;
+    pub const KEY_COMMIT: u8 = 0x06;
     pub const ACK_REQ: u8 = 0x11;
     pub const ACK_RES: u8 = 0x12;
+    pub const RESUME_INFO: u8 = 0x13;
+    pub const STREAM_JOIN: u8 = 0x14;
+    pub const JOIN_RES: u8 = 0x15;
 
     pub const FILE_BLOCK: u8 = 0x21;
     pub const FILE_END: u8 = 0x22;
+    pub const FILE_RESUME: u8 = 0x23;
+    pub const FILE_SPLIT: u8 = 0x24;
+    pub const FILE_RANGE: u8 = 0x25;
+    pub const FILE_BLOCK_COMPRESSED: u8 = 0x26;
+
+    // UDP discovery, see discovery.rs
+    pub const DISCOVER: u8 = 0x31;
+    pub const ANNOUNCE: u8 = 0x32;
+}
+
+/// protocol version of this build, version 1 had the bare PING/PONG handshake,
+/// version 2 exchanged the keys without KEY_COMMIT
+pub const PROTOCOL_VERSION: u16 = 3;
+/// oldest version this build still talks to
+pub const MIN_PROTOCOL_VERSION: u16 = 3;
+
+/// optional features, both sides use the ones they have in common
+pub mod caps {
+    pub const ENCRYPTION: u32 = 1 << 0;
+    pub const COMPRESSION: u32 = 1 << 1;
+    pub const RESUME: u32 = 1 << 2;
+    pub const DIRECTORIES: u32 = 1 << 3;
+    pub const PARALLEL_STREAMS: u32 = 1 << 4;
+    /// modification times, permissions and symlinks
+    pub const METADATA: u32 = 1 << 5;
+
+    /// everything this version supports
+    pub const ALL: u32 = ENCRYPTION | COMPRESSION | RESUME | DIRECTORIES | PARALLEL_STREAMS | METADATA;
+}
+
+/// first packet of both sides
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Hello {
+    pub version: u16,
+    pub min_version: u16,
+    pub caps: u32,
+}
+
+impl Hello {
+    pub fn ours() -> Hello {
+        Hello {
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            caps: caps::ALL,
+        }
+    }
+
+    /// the version and features both sides support, `Err` tells the user which side needs an update
+    pub fn agree(&self, peer: &Hello) -> Result<Hello, String> {
+        if peer.version < self.min_version {
+            return Err(format!(
+                "the other side speaks protocol version {}, this sfshare needs at least {}. Update sfshare there",
+                peer.version, self.min_version
+            ));
+        }
+        if self.version < peer.min_version {
+            return Err(format!(
+                "the other side needs at least protocol version {}, this sfshare speaks {}. Update sfshare here",
+                peer.min_version, self.version
+            ));
+        }
+        Ok(Hello {
+            version: self.version.min(peer.version),
+            min_version: self.min_version.max(peer.min_version),
+            caps: self.caps & peer.caps,
+        })
+    }
+
+    pub fn has(&self, cap: u32) -> bool {
+        self.caps & cap == cap
+    }
+
+    fn to_bytes(self, flag: u8) -> Box<[u8]> {
+        let mut res = Vec::with_capacity(9);
+        res.push(flag);
+        res.extend_from_slice(&self.version.to_be_bytes());
+        res.extend_from_slice(&self.min_version.to_be_bytes());
+        res.extend_from_slice(&self.caps.to_be_bytes());
+        res.into_boxed_slice()
+    }
+
+    fn read_from<R: Read>(reader: &mut R) -> io::Result<Hello> {
+        let mut b = [0u8; 8];
+        reader.read_exact(&mut b)?;
+        Ok(Hello {
+            version: u16::from_be_bytes([b[0], b[1]]),
+            min_version: u16::from_be_bytes([b[2], b[3]]),
+            caps: u32::from_be_bytes([b[4], b[5], b[6], b[7]]),
+        })
+    }
 }
 
-pub const CHECKSUM_MOD: u64 = 2147483647;
+#[test]
+fn test_hello_agree() {
+    let ours = Hello::ours();
+    let newer = Hello {
+        version: PROTOCOL_VERSION + 1,
+        min_version: MIN_PROTOCOL_VERSION,
+        caps: caps::ALL | 1 << 31,
+    };
+    let agreed = ours.agree(&newer).unwrap();
+    assert_eq!(agreed, newer.agree(&ours).unwrap());
+    assert_eq!(agreed.version, PROTOCOL_VERSION);
+    assert_eq!(agreed.caps, caps::ALL);
+
+    let no_resume = Hello { caps: caps::ENCRYPTION, ..ours };
+    assert!(!ours.agree(&no_resume).unwrap().has(caps::RESUME));
 
-#[derive(Debug)]
+    // both sides refuse, each names the side that needs an update
+    let too_new = Hello {
+        version: PROTOCOL_VERSION + 2,
+        min_version: PROTOCOL_VERSION + 1,
+        caps: caps::ALL,
+    };
+    assert!(ours.agree(&too_new).unwrap_err().ends_with("here"));
+    assert!(too_new.agree(&ours).unwrap_err().ends_with("there"));
+}
+
+/// bytes of a file the receiver already holds from an earlier, interrupted transmission
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartialFile {
+    pub id: u32,
+    pub offset: u64,
+    /// hash of the first `offset` bytes
+    pub digest: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Parsed {
+    /// an old sender, see `flags::PING`
     Ping,
-    Pong,
-    AckReq(Vec<FileMeta>),
-    AckRes(bool),
+    Hello(Hello),
+    /// answer to HELLO, the receiver closes the connection after it if the versions don't fit
+    HelloRes(Hello),
+    /// hash of the sender's public key, see `crypto::key_commitment`
+    KeyCommit([u8; 32]),
+    KeyExchange([u8; 32]),
+    AckReq {
+        files: Vec<FileMeta>,
+        hashes: Vec<HashAlgo>,
+        /// algorithms the sender wants to use, empty to send everything raw
+        compressions: Vec<Compression>,
+    },
+    AckRes {
+        accept: bool,
+        hash: HashAlgo,
+        compression: Compression,
+        /// why the request was rejected, may be empty
+        reason: String,
+        /// decision per file id, only accepted files get send
+        files: Vec<(u32, bool)>,
+    },
+    ResumeInfo(Vec<PartialFile>),
+    /// proof that this connection belongs to an existing session, see `crypto::join_proof`
+    StreamJoin([u8; 32]),
+    JoinRes(bool),
     FileBlock { id: u32, data: Vec<u8> },
-    FileEnd(u64),
+    /// block compressed with the algorithm picked in ACK_RES, `size` bytes when unpacked
+    CompressedBlock { id: u32, size: u32, data: Vec<u8> },
+    FileEnd(Vec<u8>),
+    FileResume { id: u32, offset: u64 },
+    /// the file gets send from `offset` on as ranges over the extra streams
+    FileSplit { id: u32, offset: u64 },
+    /// the following blocks of the file get written from `offset` on
+    FileRange { id: u32, offset: u64 },
+}
+
+/// list entries allocated up front, the lengths the peer sends aren't trusted
+const MAX_PREALLOC: usize = 1024;
+
+/// how much a peer may announce in one packet. `parse_with` fails with `InvalidData` on
+/// anything larger before reading it, the connection gets closed then
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    /// entries of an ACK_REQ, ACK_RES or RESUME_INFO
+    pub max_entries: u32,
+    /// bytes of an entry name, a relative path
+    pub max_name_len: usize,
+    /// bytes of a FILE_BLOCK, packed and unpacked. senders use blocks of up to
+    /// `MAX_BLOCK_SIZE`, a smaller limit fails their transfers
+    pub max_block_size: usize,
+    /// sum of the file sizes of an ACK_REQ
+    pub max_total_size: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_entries: 1_000_000,
+            max_name_len: 4096,
+            max_block_size: MAX_BLOCK_SIZE,
+            max_total_size: 1 << 50,
+        }
+    }
+}
+
+/// `what` with the amount, like "5 entries"
+fn too_large(what: String, limit: u64) -> io::Error {
+    Error::new(ErrorKind::InvalidData, format!("{}, at most {} are allowed", what, limit))
+}
+
+/// reads the `[4 byte len]` of a list
+fn read_list_len<R: Read>(reader: &mut BufReader<R>, limits: &Limits, packet: &str) -> io::Result<u32> {
+    let mut b = [0u8; 4];
+    reader.read_exact(&mut b)?;
+    let len = u32::from_be_bytes(b);
+    if len > limits.max_entries {
+        return Err(too_large(format!("{} with {} entries", packet, len), limits.max_entries as u64));
+    }
+    Ok(len)
+}
+
+fn check_block_size(len: usize, limits: &Limits) -> io::Result<()> {
+    if len > limits.max_block_size {
+        return Err(too_large(format!("file block of {} bytes", len), limits.max_block_size as u64));
+    }
+    Ok(())
 }
 
 impl Parsed {
-    pub fn to_buf(&self) -> Box<[u8]> {
-        /*
-                #[cfg(debug_assertions)]
-                    {
-                        match self {
-                            Parsed::FileBlock {id, data} => {
-                                println!("FileBlock {{ id: {}, block_size: {} }}.to_buf()", id, data.len());
-                            },
-                            p @ _ => {
-                                println!("{:?}.to_buf()", self);
-                            }
-                        }
+    /// name of the packet type for messages, without the data
+    pub fn name(&self) -> &'static str {
+        match self {
+            Parsed::Ping => "PING",
+            Parsed::Hello(_) => "HELLO",
+            Parsed::HelloRes(_) => "HELLO_RES",
+            Parsed::KeyCommit(_) => "KEY_COMMIT",
+            Parsed::KeyExchange(_) => "KEY_EXCHANGE",
+            Parsed::AckReq { .. } => "ACK_REQ",
+            Parsed::AckRes { .. } => "ACK_RES",
+            Parsed::ResumeInfo(_) => "RESUME_INFO",
+            Parsed::StreamJoin(_) => "STREAM_JOIN",
+            Parsed::JoinRes(_) => "JOIN_RES",
+            Parsed::FileBlock { .. } => "FILE_BLOCK",
+            Parsed::CompressedBlock { .. } => "FILE_BLOCK_COMPRESSED",
+            Parsed::FileEnd(_) => "FILE_END",
+            Parsed::FileResume { .. } => "FILE_RESUME",
+            Parsed::FileSplit { .. } => "FILE_SPLIT",
+            Parsed::FileRange { .. } => "FILE_RANGE",
+        }
+    }
 
-                    }
-        */
+    pub fn to_buf(&self) -> Box<[u8]> {
         match self {
             Parsed::Ping => Box::new([flags::PING]),
-            Parsed::Pong => Box::new([flags::PONG]),
-            Parsed::AckReq(fm) => {
-                let mut res = Vec::with_capacity(5 + 14 * fm.len());
+            Parsed::Hello(hello) => hello.to_bytes(flags::HELLO),
+            Parsed::HelloRes(hello) => hello.to_bytes(flags::HELLO_RES),
+            Parsed::KeyCommit(hash) => {
+                let mut res = Vec::with_capacity(33);
+                res.push(flags::KEY_COMMIT);
+                res.extend_from_slice(hash);
+                res.into_boxed_slice()
+            }
+            Parsed::KeyExchange(key) => {
+                let mut res = Vec::with_capacity(33);
+                res.push(flags::KEY_EXCHANGE);
+                res.extend_from_slice(key);
+                res.into_boxed_slice()
+            }
+            Parsed::AckReq {
+                files: fm,
+                hashes,
+                compressions,
+            } => {
+                let mut res = Vec::with_capacity(7 + hashes.len() + compressions.len() + 15 * fm.len());
 
                 res.push(flags::ACK_REQ);
+                res.push(hashes.len() as u8);
+                res.extend(hashes.iter().map(|h| h.to_byte()));
+                res.push(compressions.len() as u8);
+                res.extend(compressions.iter().map(|c| c.to_byte()));
                 let l_u32 = fm.len() as u32;
                 res.extend_from_slice(&l_u32.to_be_bytes());
 
@@ -157,34 +632,203 @@ impl Parsed {
 
                 res.into_boxed_slice()
             }
-            Parsed::AckRes(ack) => Box::new([flags::ACK_RES, (*ack) as u8]),
-            Parsed::FileBlock { id, data } => {
-                let mut res = Vec::with_capacity(7 + data.len());
+            Parsed::AckRes {
+                accept,
+                hash,
+                compression,
+                reason,
+                files,
+            } => {
+                // the reason length has 2 bytes, cut it at a char boundary
+                let mut end = reason.len().min(u16::MAX as usize);
+                while !reason.is_char_boundary(end) {
+                    end -= 1;
+                }
+                let reason = &reason.as_bytes()[..end];
 
-                res.push(flags::FILE_BLOCK);
-                res.extend_from_slice(&(*id).to_be_bytes());
+                let mut res = Vec::with_capacity(10 + reason.len() + 5 * files.len());
+                res.push(flags::ACK_RES);
+                res.push(*accept as u8);
+                res.push(hash.to_byte());
+                res.push(compression.to_byte());
+                res.extend_from_slice(&(reason.len() as u16).to_be_bytes());
+                res.extend_from_slice(reason);
+                res.extend_from_slice(&(files.len() as u32).to_be_bytes());
+                for (id, accept) in files {
+                    res.extend_from_slice(&id.to_be_bytes());
+                    res.push(*accept as u8);
+                }
+                res.into_boxed_slice()
+            }
+            Parsed::ResumeInfo(partials) => {
+                let mut res = Vec::with_capacity(5 + 45 * partials.len());
 
-                res.extend_from_slice(&(data.len() as u16).to_be_bytes());
+                res.push(flags::RESUME_INFO);
+                res.extend_from_slice(&(partials.len() as u32).to_be_bytes());
 
-                res.extend_from_slice(&data);
+                for p in partials {
+                    res.extend_from_slice(&p.id.to_be_bytes());
+                    res.extend_from_slice(&p.offset.to_be_bytes());
+                    res.push(p.digest.len() as u8);
+                    res.extend_from_slice(&p.digest);
+                }
 
                 res.into_boxed_slice()
             }
-            Parsed::FileEnd(cs) => {
-                let mut res = Vec::with_capacity(9);
+            Parsed::StreamJoin(proof) => {
+                let mut res = Vec::with_capacity(33);
+                res.push(flags::STREAM_JOIN);
+                res.extend_from_slice(proof);
+                res.into_boxed_slice()
+            }
+            Parsed::JoinRes(accept) => Box::new([flags::JOIN_RES, *accept as u8]),
+            Parsed::FileBlock { id, data } => {
+                let mut res = Vec::with_capacity(BLOCK_HEADER_SIZE + data.len());
+                res.extend_from_slice(&block_header(*id, data.len()));
+                res.extend_from_slice(data);
+                res.into_boxed_slice()
+            }
+            Parsed::CompressedBlock { id, size, data } => {
+                let mut res = Vec::with_capacity(COMPRESSED_HEADER_SIZE + data.len());
+                res.extend_from_slice(&compressed_header(*id, *size as usize, data.len()));
+                res.extend_from_slice(data);
+                res.into_boxed_slice()
+            }
+            Parsed::FileEnd(digest) => {
+                let mut res = Vec::with_capacity(2 + digest.len());
                 res.push(flags::FILE_END);
-                res.extend_from_slice(&(*cs).to_be_bytes());
+                res.push(digest.len() as u8);
+                res.extend_from_slice(digest);
                 res.into_boxed_slice()
             }
+            Parsed::FileResume { id, offset } => file_offset(flags::FILE_RESUME, *id, *offset),
+            Parsed::FileSplit { id, offset } => file_offset(flags::FILE_SPLIT, *id, *offset),
+            Parsed::FileRange { id, offset } => file_offset(flags::FILE_RANGE, *id, *offset),
+        }
+    }
+}
+
+pub fn block_header(id: u32, len: usize) -> [u8; BLOCK_HEADER_SIZE] {
+    let mut header = [0u8; BLOCK_HEADER_SIZE];
+    header[0] = flags::FILE_BLOCK;
+    header[1..5].copy_from_slice(&id.to_be_bytes());
+    header[5..].copy_from_slice(&(len as u32).to_be_bytes());
+    header
+}
+
+/// `[1 byte flag][4 byte file id][4 byte size unpacked][4 byte block size]`
+pub const COMPRESSED_HEADER_SIZE: usize = 13;
+
+pub fn compressed_header(id: u32, size: usize, len: usize) -> [u8; COMPRESSED_HEADER_SIZE] {
+    let mut header = [0u8; COMPRESSED_HEADER_SIZE];
+    header[0] = flags::FILE_BLOCK_COMPRESSED;
+    header[1..5].copy_from_slice(&id.to_be_bytes());
+    header[5..9].copy_from_slice(&(size as u32).to_be_bytes());
+    header[9..].copy_from_slice(&(len as u32).to_be_bytes());
+    header
+}
+
+/// FILE_BLOCK packet that gets reused for every block of a file,
+/// the data is read right behind the header so the packet is written with one call
+pub struct BlockBuf {
+    buf: Vec<u8>,
+}
+
+impl Default for BlockBuf {
+    fn default() -> BlockBuf {
+        BlockBuf::new()
+    }
+}
+
+impl BlockBuf {
+    pub fn new() -> BlockBuf {
+        BlockBuf {
+            buf: Vec::with_capacity(BLOCK_HEADER_SIZE + MAX_BLOCK_SIZE),
+        }
+    }
+
+    /// reads the next `len` bytes of `reader` into the packet
+    pub fn read_from<R: Read>(&mut self, id: u32, reader: &mut R, len: usize) -> io::Result<()> {
+        let len = len.min(MAX_BLOCK_SIZE);
+        self.buf.resize(BLOCK_HEADER_SIZE + len, 0);
+        self.buf[..BLOCK_HEADER_SIZE].copy_from_slice(&block_header(id, len));
+        reader.read_exact(&mut self.buf[BLOCK_HEADER_SIZE..])
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.buf[BLOCK_HEADER_SIZE..]
+    }
+
+    pub fn header(&self) -> &[u8] {
+        &self.buf[..BLOCK_HEADER_SIZE]
+    }
+
+    /// header and data
+    pub fn packet(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+/// connections FILE_BLOCKs get written to
+pub trait BlockWrite: Write {
+    /// writes `block`, which holds the bytes of `file` at `offset`
+    fn write_block(&mut self, block: &BlockBuf, _file: &File, _offset: u64) -> io::Result<()> {
+        self.write_all(block.packet())
+    }
+}
+
+impl<W: ConnWrite> BlockWrite for SecureWriter<W> {
+    /// unencrypted connections can send the data straight from the page cache (sendfile)
+    fn write_block(&mut self, block: &BlockBuf, file: &File, offset: u64) -> io::Result<()> {
+        if let Some(conn) = self.plain_mut() {
+            conn.write_all(block.header())?;
+            return match conn.send_file(file, offset, block.data().len()) {
+                Some(res) => res,
+                None => conn.write_all(block.data()),
+            };
         }
+        self.write_all(block.packet())
     }
 }
 
+/// structure : [1 byte flag][4 byte file-id][8 byte offset]
+fn file_offset(flag: u8, id: u32, offset: u64) -> Box<[u8]> {
+    let mut res = Vec::with_capacity(13);
+    res.push(flag);
+    res.extend_from_slice(&id.to_be_bytes());
+    res.extend_from_slice(&offset.to_be_bytes());
+    res.into_boxed_slice()
+}
+
+/// reads `[4 byte file-id][8 byte offset]`
+fn parse_file_offset<R: Read>(reader: &mut BufReader<R>) -> io::Result<(u32, u64)> {
+    let mut b = [0u8; 12];
+    reader.read_exact(&mut b)?;
+    let mut id = [0u8; 4];
+    let mut offset = [0u8; 8];
+    id.copy_from_slice(&b[0..4]);
+    offset.copy_from_slice(&b[4..12]);
+    Ok((u32::from_be_bytes(id), u64::from_be_bytes(offset)))
+}
+
+use crate::compress::Compression;
+use crate::conn::ConnWrite;
+use crate::crypto::SecureWriter;
+use crate::hash::HashAlgo;
+use std::convert::TryInto;
+use std::fs::File;
 use std::io::{self, BufReader, Error, ErrorKind, Read, Write};
-use std::net::TcpStream;
 use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-pub fn parse(reader: &mut BufReader<TcpStream>) -> io::Result<Parsed> {
+/// `parse_with` the default limits
+pub fn parse<R: Read>(reader: &mut BufReader<R>) -> io::Result<Parsed> {
+    parse_with(reader, &Limits::default())
+}
+
+/// reads the next packet. `InvalidData` errors leave the rest of the packet unread,
+/// the connection can't be used after them
+pub fn parse_with<R: Read>(reader: &mut BufReader<R>, limits: &Limits) -> io::Result<Parsed> {
     let packet_type: u8 = {
         let mut d = [0u8];
         reader.read_exact(&mut d)?;
@@ -194,64 +838,217 @@ pub fn parse(reader: &mut BufReader<TcpStream>) -> io::Result<Parsed> {
 
     match packet_type {
         flags::PING => return Ok(Parsed::Ping),
-        flags::PONG => return Ok(Parsed::Pong),
+        flags::HELLO => return Ok(Parsed::Hello(Hello::read_from(reader)?)),
+        flags::HELLO_RES => return Ok(Parsed::HelloRes(Hello::read_from(reader)?)),
+        flags::KEY_COMMIT => {
+            let mut hash = [0u8; 32];
+            reader.read_exact(&mut hash)?;
+            return Ok(Parsed::KeyCommit(hash));
+        }
+        flags::KEY_EXCHANGE => {
+            let mut key = [0u8; 32];
+            reader.read_exact(&mut key)?;
+            return Ok(Parsed::KeyExchange(key));
+        }
         flags::ACK_REQ => {
-            let mut list_len = [0u8; 4];
-            reader.read_exact(&mut list_len)?;
+            let mut hash_count = [0u8];
+            reader.read_exact(&mut hash_count)?;
+            let mut hash_ids = vec![0u8; hash_count[0] as usize];
+            reader.read_exact(&mut hash_ids)?;
+            // skip algorithms of newer versions
+            let hashes = hash_ids.into_iter().filter_map(HashAlgo::from_byte).collect();
+
+            let mut compression_count = [0u8];
+            reader.read_exact(&mut compression_count)?;
+            let mut compression_ids = vec![0u8; compression_count[0] as usize];
+            reader.read_exact(&mut compression_ids)?;
+            let compressions = compression_ids.into_iter().filter_map(Compression::from_byte).collect();
 
-            let list_len = u32::from_be_bytes(list_len);
-            let mut meta = Vec::with_capacity(list_len as usize);
+            let list_len = read_list_len(reader, limits, "ACK_REQ")?;
+            let mut meta = Vec::with_capacity((list_len as usize).min(MAX_PREALLOC));
+            let mut total: u64 = 0;
 
             for i in 0..list_len {
                 // parse next list item
-                match FileMeta::from_byte_stream(reader) {
-                    Ok(fm) => meta.push(fm),
-                    Err(_) => {
-                        eprintln!("could not construct FileMeta for {}th file", i);
-                        return Err(io::Error::from(ErrorKind::InvalidData));
-                    }
+                let fm = FileMeta::from_byte_stream(reader, limits)
+                    .map_err(|e| Error::new(e.kind(), format!("entry {} of ACK_REQ: {}", i, e)))?;
+                total = total.saturating_add(fm.size);
+                if total > limits.max_total_size {
+                    return Err(too_large(format!("ACK_REQ with {} bytes of files", total), limits.max_total_size));
                 }
+                meta.push(fm);
             }
 
-            return Ok(Parsed::AckReq(meta));
+            return Ok(Parsed::AckReq {
+                files: meta,
+                hashes,
+                compressions,
+            });
         }
         flags::ACK_RES => {
-            let mut b = [0u8];
+            let mut b = [0u8; 5];
             reader.read_exact(&mut b)?;
-            return Ok(Parsed::AckRes(b[0] != 0));
+            let hash = HashAlgo::from_byte(b[1])
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "unknown hash algorithm"))?;
+            let compression = Compression::from_byte(b[2])
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "unknown compression"))?;
+
+            let mut reason = vec![0u8; u16::from_be_bytes([b[3], b[4]]) as usize];
+            reader.read_exact(&mut reason)?;
+
+            let list_len = read_list_len(reader, limits, "ACK_RES")?;
+            let mut files = Vec::with_capacity((list_len as usize).min(MAX_PREALLOC));
+            for _ in 0..list_len {
+                let mut entry = [0u8; 5];
+                reader.read_exact(&mut entry)?;
+                let id = u32::from_be_bytes([entry[0], entry[1], entry[2], entry[3]]);
+                files.push((id, entry[4] != 0));
+            }
+
+            return Ok(Parsed::AckRes {
+                accept: b[0] != 0,
+                hash,
+                compression,
+                // the reason is only displayed, don't fail on broken utf8
+                reason: String::from_utf8_lossy(&reason).into_owned(),
+                files,
+            });
+        }
+        flags::RESUME_INFO => {
+            let list_len = read_list_len(reader, limits, "RESUME_INFO")?;
+            let mut partials = Vec::with_capacity((list_len as usize).min(MAX_PREALLOC));
+
+            for _ in 0..list_len {
+                let mut b = [0u8; 13];
+                reader.read_exact(&mut b)?;
+
+                let mut id = [0u8; 4];
+                let mut offset = [0u8; 8];
+                id.copy_from_slice(&b[0..4]);
+                offset.copy_from_slice(&b[4..12]);
+
+                let mut digest = vec![0u8; b[12] as usize];
+                reader.read_exact(&mut digest)?;
+
+                partials.push(PartialFile {
+                    id: u32::from_be_bytes(id),
+                    offset: u64::from_be_bytes(offset),
+                    digest,
+                });
+            }
+
+            return Ok(Parsed::ResumeInfo(partials));
         }
         flags::FILE_BLOCK => {
             let mut b: [u8; 4] = [0; 4];
             reader.read_exact(&mut b)?;
             let f_id = u32::from_be_bytes(b);
-            let mut b: [u8; 2] = [0; 2];
             reader.read_exact(&mut b)?;
-            let b_size = u16::from_be_bytes(b);
-
-            let mut data = vec![0u8; b_size as usize];
+            let b_size = u32::from_be_bytes(b) as usize;
+            check_block_size(b_size, limits)?;
 
+            let mut data = vec![0u8; b_size];
             reader.read_exact(&mut data)?;
 
-            assert_eq!(data.len(), b_size as usize);
-
             return Ok(Parsed::FileBlock { id: f_id, data });
         }
+        flags::FILE_BLOCK_COMPRESSED => {
+            let mut b = [0u8; 12];
+            reader.read_exact(&mut b)?;
+            let id = u32::from_be_bytes([b[0], b[1], b[2], b[3]]);
+            let size = u32::from_be_bytes([b[4], b[5], b[6], b[7]]);
+            let len = u32::from_be_bytes([b[8], b[9], b[10], b[11]]) as usize;
+            check_block_size(size as usize, limits)?;
+            check_block_size(len, limits)?;
+
+            let mut data = vec![0u8; len];
+            reader.read_exact(&mut data)?;
+            return Ok(Parsed::CompressedBlock { id, size, data });
+        }
         flags::FILE_END => {
-            let mut b = [0u8; 8];
+            let mut len = [0u8];
+            reader.read_exact(&mut len)?;
+            let mut digest = vec![0u8; len[0] as usize];
+            reader.read_exact(&mut digest)?;
+            return Ok(Parsed::FileEnd(digest));
+        }
+        flags::STREAM_JOIN => {
+            let mut proof = [0u8; 32];
+            reader.read_exact(&mut proof)?;
+            return Ok(Parsed::StreamJoin(proof));
+        }
+        flags::JOIN_RES => {
+            let mut b = [0u8];
             reader.read_exact(&mut b)?;
-            return Ok(Parsed::FileEnd(u64::from_be_bytes(b)));
+            return Ok(Parsed::JoinRes(b[0] != 0));
+        }
+        flags::FILE_RESUME => {
+            let (id, offset) = parse_file_offset(reader)?;
+            return Ok(Parsed::FileResume { id, offset });
+        }
+        flags::FILE_SPLIT => {
+            let (id, offset) = parse_file_offset(reader)?;
+            return Ok(Parsed::FileSplit { id, offset });
+        }
+        flags::FILE_RANGE => {
+            let (id, offset) = parse_file_offset(reader)?;
+            return Ok(Parsed::FileRange { id, offset });
         }
         _ => {}
     }
 
     Err(Error::new(
         ErrorKind::InvalidData,
-        "Can't parse stream: unknown packet type",
+        format!("Can't parse stream: unknown packet type {:#04x}", packet_type),
     ))
 }
 
+#[test]
+fn test_limits() {
+    let limits = Limits {
+        max_entries: 2,
+        max_total_size: 100,
+        ..Limits::default()
+    };
+    let fails = |packet: &[u8]| {
+        let err = parse_with(&mut BufReader::new(packet), &limits).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData, "{}", err);
+    };
+
+    // list lengths fail before any entry arrives
+    fails(&[flags::ACK_REQ, 0, 0, 0xff, 0xff, 0xff, 0xff]);
+    fails(&[flags::ACK_RES, 1, 0, 0, 0, 0, 0, 0, 0, 3]);
+    fails(&[flags::RESUME_INFO, 0xff, 0xff, 0xff, 0xff]);
+    fails(&block_header(1, MAX_BLOCK_SIZE + 1));
+    fails(&compressed_header(1, MAX_BLOCK_SIZE + 1, 10));
+    fails(&[0x7f, 0, 0, 0]);
+
+    let file = |size| FileMeta {
+        size,
+        id: 0,
+        name: String::from("a"),
+        kind: EntryKind::File,
+        path: None,
+        mtime: None,
+        mode: None,
+        link: None,
+    };
+    let request = |files| Parsed::AckReq {
+        files,
+        hashes: Vec::new(),
+        compressions: Vec::new(),
+    };
+    fails(&request(vec![file(60), file(60)]).to_buf());
+    fails(&request(vec![file(u64::MAX), file(u64::MAX)]).to_buf());
+    let fits = request(vec![file(60), file(40)]);
+    assert_eq!(parse_with(&mut BufReader::new(&fits.to_buf()[..]), &limits).unwrap(), fits);
+
+    let long = FileMeta { name: "x".repeat(300), ..file(1) }.to_byte_stream();
+    let short = Limits { max_name_len: 255, ..Limits::default() };
+    let err = FileMeta::from_byte_stream(&mut BufReader::new(&long[..]), &short).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+}
+
 pub fn send_slice<W: Write>(stream: &mut W, data: &[u8]) -> io::Result<()> {
-    #[cfg(debug_assertions)]
-    println!("sending {} bytes", data.len());
     stream.write_all(data)
 }
@@ -0,0 +1,86 @@
+//! Finding receivers by name, with the receiver binary in its own process and a `Receiver`
+//! of the library. Both use the real discovery port, the steps run one after another.
+
+use sfshare::conn::{Connection, Listen};
+use sfshare::discovery::{self, DISCOVERY_PORT};
+use sfshare::paths::DownloadDir;
+use sfshare::{Endpoint, Receiver};
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// if a receiver called `name` answers within `wait`
+fn found(name: &str, wait: Duration) -> bool {
+    let deadline = Instant::now() + wait;
+    while Instant::now() < deadline {
+        let receivers = discovery::find_receivers(DISCOVERY_PORT, Duration::from_millis(300), &|_| {}).unwrap();
+        if receivers.iter().any(|r| r.name == name) {
+            return true;
+        }
+    }
+    false
+}
+
+/// long enough for a receiver that just started
+const START: Duration = Duration::from_secs(5);
+/// a receiver that stopped might still have been answering a moment ago
+const GONE: Duration = Duration::from_secs(1);
+
+/// pretends to listen on `addr` until something arrives on `end`
+struct Idle {
+    addr: SocketAddr,
+    end: mpsc::Receiver<()>,
+}
+
+impl Listen for Idle {
+    fn accept(&mut self) -> Option<io::Result<Box<dyn Connection>>> {
+        let _ = self.end.recv();
+        None
+    }
+
+    fn endpoint(&self) -> io::Result<Endpoint> {
+        Ok(Endpoint::Tcp(self.addr))
+    }
+}
+
+#[test]
+fn discovery() {
+    let dir = std::env::temp_dir().join(format!("sfshare_discovery_{}", std::process::id()));
+    let name = format!("sfshare-test-{}", std::process::id());
+
+    // the receiver binary announces itself to other processes
+    let mut child = Command::new(env!("CARGO_BIN_EXE_sfshare"))
+        .args(["recv", &name, "--port", "0", "-o"])
+        .arg(&dir)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let seen = found(&name, START);
+    child.kill().unwrap();
+    child.wait().unwrap();
+    assert!(seen, "the receiver process wasn't found");
+    assert!(!found(&name, GONE), "the receiver process is still found after it ended");
+
+    // a receiver of the library stops answering when it stops running
+    let (end, idle) = mpsc::channel();
+    let receiver = Receiver::new(DownloadDir::new(&dir, true).unwrap()).announce(name.clone());
+    let running = thread::spawn(move || {
+        let mut listener = Idle {
+            addr: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 1),
+            end: idle,
+        };
+        receiver.run_on(&mut listener)
+    });
+    assert!(found(&name, START), "the receiver wasn't found");
+    end.send(()).unwrap();
+    running.join().unwrap().unwrap();
+    assert!(!found(&name, GONE), "the receiver is still found after run_on returned");
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
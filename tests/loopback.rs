@@ -0,0 +1,594 @@
+//! A receiver on an ephemeral loopback port, driven by a sender of the library.
+//! Misbehaving senders (wrong hashes, lost connections) speak the protocol by hand.
+//! `via_command` runs the receiver binary over stdin/stdout instead.
+
+use sfshare::crypto::{self, SecureReader, SecureWriter};
+use sfshare::hash::{self, HashAlgo};
+use sfshare::paths::{self, DownloadDir};
+use sfshare::policy::AcceptPolicy;
+use sfshare::transport::{self, EntryKind, FileMeta, Hello, Limits, Parsed};
+use sfshare::{Endpoint, Error, Event, Manifest, Peer, Receiver, Sender};
+
+use std::collections::HashSet;
+use std::io::{self, BufReader, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+/// nothing here takes that long, a hanging test fails instead
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// an empty directory for `test`, with `src` for the files to send
+fn scratch(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("sfshare_loopback_{}_{}", test, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("src")).unwrap();
+    dir
+}
+
+/// a running receiver, it stores into `out`. the directory of the test goes away with it
+struct Loopback {
+    addr: SocketAddr,
+    dir: PathBuf,
+    out: PathBuf,
+    events: mpsc::Receiver<Event>,
+}
+
+impl Drop for Loopback {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// starts a receiver storing into `dir/out`, `setup` sets its policy etc.
+fn receiver(dir: &Path, setup: impl FnOnce(Receiver) -> Receiver) -> Loopback {
+    let out = dir.join("out");
+    let (tx, events) = mpsc::channel();
+    let receiver = Receiver::new(DownloadDir::new(&out, true).unwrap())
+        .bind(Ipv4Addr::LOCALHOST.into())
+        .port(0)
+        .on_event(move |e| {
+            let _ = tx.send(e.clone());
+        });
+    let receiver = setup(receiver);
+    // runs until the test process ends
+    thread::spawn(move || receiver.run());
+
+    match events.recv_timeout(TIMEOUT) {
+        Ok(Event::Listening {
+            addr: Endpoint::Tcp(addr),
+        }) => Loopback {
+            addr,
+            dir: dir.to_path_buf(),
+            out,
+            events,
+        },
+        e => panic!("receiver didn't start: {:?}", e),
+    }
+}
+
+/// accepts everything without asking
+fn accept_all(receiver: Receiver) -> Receiver {
+    receiver.policy(AcceptPolicy {
+        yes: true,
+        ..AcceptPolicy::default()
+    })
+}
+
+impl Loopback {
+    /// events until the connection carrying a request is closed
+    fn wait_done(&self) -> Vec<Event> {
+        let mut seen = Vec::new();
+        // connections that got a request, extra streams don't
+        let mut requests: HashSet<Peer> = HashSet::new();
+        loop {
+            let event = self.events.recv_timeout(TIMEOUT).expect("receiver didn't finish the request");
+            match &event {
+                Event::Request(req) => {
+                    requests.insert(req.peer.clone());
+                }
+                Event::Rejected { peer, .. } | Event::Error { peer: Some(peer), .. } => {
+                    requests.insert(peer.clone());
+                }
+                Event::Closed { peer } if requests.contains(peer) => {
+                    seen.push(event);
+                    return seen;
+                }
+                _ => {}
+            }
+            seen.push(event);
+        }
+    }
+
+    fn send(&self, manifest: &Manifest, setup: impl FnOnce(Sender) -> Sender) -> (sfshare::Result<()>, Vec<Event>) {
+        let sent = setup(Sender::new(self.addr)).send(manifest);
+        (sent, self.wait_done())
+    }
+}
+
+fn manifest_of(paths: &[&Path]) -> Manifest {
+    let mut manifest = Manifest::new();
+    for p in paths {
+        manifest.add(p).unwrap();
+    }
+    manifest
+}
+
+/// reproducible bytes that don't compress
+fn noise(len: usize, seed: u64) -> Vec<u8> {
+    let mut x = seed | 1;
+    (0..len)
+        .map(|_| {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x as u8
+        })
+        .collect()
+}
+
+/// every file and directory below `a` exists below `b` with the same content
+fn assert_same_tree(a: &Path, b: &Path) {
+    for entry in std::fs::read_dir(a).unwrap() {
+        let path = entry.unwrap().path();
+        let other = b.join(path.file_name().unwrap());
+        if path.is_dir() {
+            assert!(other.is_dir(), "{:?} is missing", other);
+            assert_same_tree(&path, &other);
+        } else {
+            let got = std::fs::read(&other).unwrap_or_else(|e| panic!("{:?}: {}", other, e));
+            assert!(got == std::fs::read(&path).unwrap(), "{:?} differs", other);
+        }
+    }
+}
+
+fn files_in(dir: &Path) -> Vec<PathBuf> {
+    match std::fs::read_dir(dir) {
+        Ok(entries) => entries.map(|e| e.unwrap().path()).collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+#[test]
+fn single_file() {
+    let dir = scratch("single");
+    let lb = receiver(&dir, accept_all);
+    let src = dir.join("src/hello.txt");
+    std::fs::write(&src, b"hello world").unwrap();
+
+    let (sent, events) = lb.send(&manifest_of(&[&src]), |s| s);
+    sent.unwrap();
+
+    let stored = lb.out.join("hello.txt");
+    assert_eq!(std::fs::read(&stored).unwrap(), b"hello world");
+    assert!(events
+        .iter()
+        .any(|e| matches!(e, Event::FileDone { path: Some(p), .. } if *p == stored)));
+    assert!(!paths::part_path(&stored).exists());
+}
+
+#[test]
+fn many_files() {
+    let dir = scratch("many");
+    let lb = receiver(&dir, accept_all);
+    let root = dir.join("src/tree");
+    for i in 0..150 {
+        let sub = root.join(format!("d{}/e{}", i % 7, i % 3));
+        std::fs::create_dir_all(&sub).unwrap();
+        std::fs::write(sub.join(format!("f{}.bin", i)), noise(i * 37, i as u64)).unwrap();
+    }
+
+    let (sent, events) = lb.send(&manifest_of(&[&root]), |s| s);
+    sent.unwrap();
+
+    assert_same_tree(&root, &lb.out.join("tree"));
+    let done = events.iter().filter(|e| matches!(e, Event::FileDone { .. })).count();
+    assert_eq!(done, 150);
+}
+
+#[test]
+fn empty_files() {
+    let dir = scratch("empty");
+    let lb = receiver(&dir, accept_all);
+    let root = dir.join("src/things");
+    std::fs::create_dir_all(root.join("nothing")).unwrap();
+    std::fs::create_dir_all(root.join("sub")).unwrap();
+    std::fs::write(root.join("sub/empty.txt"), b"").unwrap();
+    let single = dir.join("src/zero.bin");
+    std::fs::write(&single, b"").unwrap();
+
+    let (sent, _) = lb.send(&manifest_of(&[&root, &single]), |s| s);
+    sent.unwrap();
+
+    assert!(lb.out.join("things/nothing").is_dir());
+    assert_eq!(std::fs::metadata(lb.out.join("things/sub/empty.txt")).unwrap().len(), 0);
+    assert_eq!(std::fs::metadata(lb.out.join("zero.bin")).unwrap().len(), 0);
+}
+
+#[test]
+fn large_file() {
+    let dir = scratch("large");
+    let lb = receiver(&dir, accept_all);
+    let src = dir.join("src/large.bin");
+    // larger than the 4 MiB ranges of parallel streams, debug builds encrypt slowly
+    let data = noise(5 * 1024 * 1024 + 12345, 7);
+    std::fs::write(&src, &data).unwrap();
+
+    // split over parallel streams
+    let (sent, _) = lb.send(&manifest_of(&[&src]), |s| s.streams(3));
+    sent.unwrap();
+    assert!(std::fs::read(lb.out.join("large.bin")).unwrap() == data);
+
+    // one connection, the existing file gets renamed
+    let (sent, _) = lb.send(&manifest_of(&[&src]), |s| s);
+    sent.unwrap();
+    assert!(std::fs::read(lb.out.join("large (1).bin")).unwrap() == data);
+}
+
+/// the protocol over the pipes of `sfshare recv --stdio`, like `send --via "ssh host sfshare recv --stdio"`
+#[test]
+fn via_command() {
+    let dir = scratch("via");
+    let root = dir.join("src/docs");
+    std::fs::create_dir_all(root.join("sub")).unwrap();
+    std::fs::write(root.join("a.txt"), b"over a pipe").unwrap();
+    std::fs::write(root.join("sub/b.bin"), noise(300_000, 11)).unwrap();
+    let out = dir.join("out");
+
+    let command = format!("{:?} recv --stdio --yes -o {:?}", env!("CARGO_BIN_EXE_sfshare"), out);
+    let sent = Sender::new(Endpoint::Command(command)).send(&manifest_of(&[&root]));
+    let copied = std::panic::catch_unwind(|| assert_same_tree(&root, &out.join("docs")));
+    let _ = std::fs::remove_dir_all(&dir);
+    sent.unwrap();
+    copied.unwrap();
+}
+
+#[test]
+fn rejection() {
+    let dir = scratch("rejection");
+    let a = dir.join("src/a.txt");
+    let b = dir.join("src/b.txt");
+    std::fs::write(&a, b"a").unwrap();
+    std::fs::write(&b, b"b").unwrap();
+    let manifest = manifest_of(&[&a, &b]);
+
+    // by the policy, the sender gets the reason
+    let lb = receiver(&dir, |r| {
+        r.policy(AcceptPolicy {
+            yes: true,
+            max_files: Some(1),
+            ..AcceptPolicy::default()
+        })
+    });
+    let (sent, events) = lb.send(&manifest, |s| s);
+    match sent {
+        Err(Error::Rejected(text)) => assert!(text.contains("limit"), "{}", text),
+        r => panic!("not rejected: {:?}", r),
+    }
+    assert!(events.iter().any(|e| matches!(e, Event::Rejected { .. })));
+    assert!(files_in(&lb.out).is_empty());
+
+    // by the user
+    let lb = receiver(&dir, |r| r.on_ask(|_| None));
+    let (sent, _) = lb.send(&manifest, |s| s);
+    assert!(matches!(sent, Err(Error::Rejected(_))));
+    assert!(files_in(&lb.out).is_empty());
+}
+
+/// a sender doing HELLO and ACK_REQ by hand, unencrypted. returns the hash the receiver picked
+fn raw_request(addr: SocketAddr, files: Vec<FileMeta>) -> io::Result<(TcpStream, BufReader<TcpStream>, HashAlgo)> {
+    let mut stream = TcpStream::connect(addr)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    stream.write_all(&Parsed::Hello(Hello::ours()).to_buf())?;
+    assert!(matches!(transport::parse(&mut reader)?, Parsed::HelloRes(_)));
+
+    let request = Parsed::AckReq {
+        files,
+        hashes: hash::SUPPORTED.to_vec(),
+        compressions: Vec::new(),
+    };
+    stream.write_all(&request.to_buf())?;
+    let algo = match transport::parse(&mut reader)? {
+        Parsed::AckRes { accept: true, hash, .. } => hash,
+        p => panic!("request not accepted: {:?}", p),
+    };
+    assert!(matches!(transport::parse(&mut reader)?, Parsed::ResumeInfo(_)));
+    Ok((stream, reader, algo))
+}
+
+fn file_meta(name: &str, size: u64) -> FileMeta {
+    FileMeta {
+        size,
+        id: 0,
+        name: String::from(name),
+        kind: EntryKind::File,
+        path: None,
+        mtime: None,
+        mode: None,
+        link: None,
+    }
+}
+
+/// accepts unencrypted connections, the hand made senders don't encrypt
+fn accept_plain(receiver: Receiver) -> Receiver {
+    receiver.policy(AcceptPolicy {
+        yes: true,
+        allow_unencrypted: true,
+        ..AcceptPolicy::default()
+    })
+}
+
+#[test]
+fn checksum_mismatch() -> io::Result<()> {
+    let dir = scratch("checksum");
+    let lb = receiver(&dir, accept_plain);
+    let data = noise(100_000, 3);
+
+    let (mut stream, _reader, algo) = raw_request(lb.addr, vec![file_meta("bad.bin", data.len() as u64)])?;
+    stream.write_all(&Parsed::FileBlock { id: 0, data: data.clone() }.to_buf())?;
+    // the hash of other data
+    let mut hasher = hash::Hasher::new(algo);
+    hasher.update(b"something else");
+    stream.write_all(&Parsed::FileEnd(hasher.finish()).to_buf())?;
+
+    let events = lb.wait_done();
+    assert!(events.iter().any(
+        |e| matches!(e, Event::Error { error: Error::Checksum(files), .. } if files == &["bad.bin"])
+    ));
+    let stored = lb.out.join("bad.bin");
+    assert!(!stored.exists());
+    assert!(!paths::part_path(&stored).exists());
+    Ok(())
+}
+
+#[test]
+fn disconnect_mid_transfer() -> io::Result<()> {
+    let dir = scratch("disconnect");
+    let lb = receiver(&dir, accept_plain);
+    let src = dir.join("src/part.bin");
+    let data = noise(300_000, 5);
+    std::fs::write(&src, &data)?;
+
+    let (stream, _reader, _) = raw_request(lb.addr, vec![file_meta("part.bin", data.len() as u64)])?;
+    (&stream).write_all(&Parsed::FileBlock { id: 0, data: data[..120_000].to_vec() }.to_buf())?;
+    stream.shutdown(Shutdown::Both)?;
+
+    let events = lb.wait_done();
+    assert!(events.iter().any(|e| matches!(e, Event::Error { error: Error::Network(_), .. })));
+    let stored = lb.out.join("part.bin");
+    assert!(!stored.exists());
+    assert_eq!(std::fs::metadata(paths::part_path(&stored))?.len(), 120_000);
+
+    // sending it again continues where it stopped
+    let (sent, events) = lb.send(&manifest_of(&[&src]), |s| s);
+    sent.unwrap();
+    assert!(events
+        .iter()
+        .any(|e| matches!(e, Event::Message { text, .. } if text.starts_with("Resuming part.bin"))));
+    assert!(std::fs::read(&stored)? == data);
+    assert!(!paths::part_path(&stored).exists());
+    Ok(())
+}
+
+#[test]
+fn oversized_packets() -> io::Result<()> {
+    let dir = scratch("limits");
+    let lb = receiver(&dir, |r| {
+        accept_plain(r).limits(Limits {
+            max_entries: 2,
+            ..Limits::default()
+        })
+    });
+    let hello = |stream: &mut TcpStream| -> io::Result<BufReader<TcpStream>> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        stream.write_all(&Parsed::Hello(Hello::ours()).to_buf())?;
+        assert!(matches!(transport::parse(&mut reader)?, Parsed::HelloRes(_)));
+        Ok(reader)
+    };
+
+    // too many entries and garbage both end the connection
+    let too_many = Parsed::AckReq {
+        files: vec![file_meta("a", 1), file_meta("b", 1), file_meta("c", 1)],
+        hashes: hash::SUPPORTED.to_vec(),
+        compressions: Vec::new(),
+    };
+    for packet in [too_many.to_buf(), Box::new([0x7f, 1, 2, 3])] {
+        let mut stream = TcpStream::connect(lb.addr)?;
+        let mut reader = hello(&mut stream)?;
+        stream.write_all(&packet)?;
+
+        let events = lb.wait_done();
+        assert!(events.iter().any(|e| matches!(e, Event::Error { error: Error::Protocol(_), .. })));
+        assert!(transport::parse(&mut reader).is_err());
+    }
+
+    // more data than announced for a file
+    let (mut stream, _reader, _) = raw_request(lb.addr, vec![file_meta("tiny.bin", 10)])?;
+    stream.write_all(&Parsed::FileBlock { id: 0, data: vec![1; 6] }.to_buf())?;
+    stream.write_all(&Parsed::FileBlock { id: 0, data: vec![2; 6] }.to_buf())?;
+    let events = lb.wait_done();
+    assert!(events.iter().any(|e| matches!(e, Event::Error { error: Error::Protocol(_), .. })));
+    assert!(!lb.out.join("tiny.bin").exists());
+
+    // the receiver keeps going
+    let src = dir.join("src/fits.txt");
+    std::fs::write(&src, b"fits")?;
+    let (sent, _) = lb.send(&manifest_of(&[&src]), |s| s);
+    sent.unwrap();
+    assert_eq!(std::fs::read(lb.out.join("fits.txt"))?, b"fits");
+    Ok(())
+}
+
+#[test]
+fn resume_mid_file() -> io::Result<()> {
+    let dir = scratch("resume-mid-file");
+    let lb = receiver(&dir, accept_plain);
+    std::fs::write(paths::part_path(&lb.out.join("b.bin")), b"12345")?;
+
+    // b.bin could be resumed, but not while a.bin is half written
+    let files = vec![file_meta("a.bin", 10), FileMeta { id: 1, ..file_meta("b.bin", 20) }];
+    let (mut stream, _reader, _) = raw_request(lb.addr, files)?;
+    stream.write_all(&Parsed::FileBlock { id: 0, data: vec![1; 4] }.to_buf())?;
+    stream.write_all(&Parsed::FileResume { id: 1, offset: 5 }.to_buf())?;
+
+    let events = lb.wait_done();
+    assert!(events.iter().any(|e| matches!(e, Event::Error { error: Error::Protocol(_), .. })));
+    assert!(!lb.out.join("a.bin").exists());
+    assert!(!lb.out.join("b.bin").exists());
+    Ok(())
+}
+
+#[test]
+fn split_mid_file() -> io::Result<()> {
+    let dir = scratch("split-mid-file");
+    let lb = receiver(&dir, accept_plain);
+    let files = || vec![file_meta("a.bin", 10), FileMeta { id: 1, ..file_meta("b.bin", 20) }];
+
+    // a parallel file can't start while a.bin is half written
+    let (mut stream, _reader, _) = raw_request(lb.addr, files())?;
+    stream.write_all(&Parsed::FileBlock { id: 0, data: vec![1; 4] }.to_buf())?;
+    stream.write_all(&Parsed::FileSplit { id: 1, offset: 0 }.to_buf())?;
+    let events = lb.wait_done();
+    assert!(events.iter().any(|e| matches!(e, Event::Error { error: Error::Protocol(_), .. })));
+
+    // and blocks of a parallel file don't come over the main connection
+    let (mut stream, _reader, _) = raw_request(lb.addr, files())?;
+    stream.write_all(&Parsed::FileSplit { id: 1, offset: 0 }.to_buf())?;
+    stream.write_all(&Parsed::FileBlock { id: 1, data: vec![1; 4] }.to_buf())?;
+    let events = lb.wait_done();
+    assert!(events.iter().any(|e| matches!(e, Event::Error { error: Error::Protocol(_), .. })));
+    let stored = lb.out.join("b.bin");
+    assert!(!stored.exists());
+    assert!(!paths::part_path(&stored).exists());
+    Ok(())
+}
+
+#[test]
+fn uncommitted_key() -> io::Result<()> {
+    let dir = scratch("commit");
+    let lb = receiver(&dir, accept_all);
+    let mut stream = TcpStream::connect(lb.addr)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    stream.write_all(&Parsed::Hello(Hello::ours()).to_buf())?;
+    assert!(matches!(transport::parse(&mut reader)?, Parsed::HelloRes(_)));
+
+    // a key picked after seeing the receiver's one could match any verification code
+    stream.write_all(&Parsed::KeyExchange(crypto::Handshake::new().public_key()).to_buf())?;
+    let events = lb.wait_done();
+    assert!(events.iter().any(|e| matches!(e, Event::Error { error: Error::Protocol(_), .. })));
+    Ok(())
+}
+
+/// an encrypted connection of a hand made sender, after HELLO and the key exchange
+struct Encrypted {
+    writer: SecureWriter<TcpStream>,
+    reader: BufReader<SecureReader<TcpStream>>,
+    session: crypto::Session,
+}
+
+fn encrypted(addr: SocketAddr) -> io::Result<Encrypted> {
+    let stream = TcpStream::connect(addr)?;
+    let mut reader = BufReader::new(SecureReader::new(stream.try_clone()?));
+    let mut writer = SecureWriter::new(stream);
+    writer.write_all(&Parsed::Hello(Hello::ours()).to_buf())?;
+    assert!(matches!(transport::parse(&mut reader)?, Parsed::HelloRes(_)));
+
+    let handshake = crypto::Handshake::new();
+    writer.write_all(&Parsed::KeyCommit(handshake.commitment()).to_buf())?;
+    let peer_key = match transport::parse(&mut reader)? {
+        Parsed::KeyExchange(key) => key,
+        p => panic!("expected KEY_EXCHANGE, got {:?}", p),
+    };
+    writer.write_all(&Parsed::KeyExchange(handshake.public_key()).to_buf())?;
+    let keys = handshake.finish(crypto::Role::Sender, peer_key)?;
+    writer.start_encryption(keys.send);
+    crypto::start_decryption(&mut reader, keys.recv)?;
+    Ok(Encrypted {
+        writer,
+        reader,
+        session: keys.session,
+    })
+}
+
+#[test]
+fn extra_stream_ends_early() -> io::Result<()> {
+    let dir = scratch("ranges");
+    let lb = receiver(&dir, accept_all);
+
+    let mut main = encrypted(lb.addr)?;
+    let request = Parsed::AckReq {
+        files: vec![file_meta("split.bin", 100_000)],
+        hashes: hash::SUPPORTED.to_vec(),
+        compressions: Vec::new(),
+    };
+    main.writer.write_all(&request.to_buf())?;
+    assert!(matches!(transport::parse(&mut main.reader)?, Parsed::AckRes { accept: true, .. }));
+    assert!(matches!(transport::parse(&mut main.reader)?, Parsed::ResumeInfo(_)));
+    main.writer.write_all(&Parsed::FileSplit { id: 0, offset: 0 }.to_buf())?;
+
+    // the only extra stream sends half of its range and closes cleanly
+    let mut extra = encrypted(lb.addr)?;
+    let proof = crypto::join_proof(&main.session.stream_key, &extra.session.binding);
+    extra.writer.write_all(&Parsed::StreamJoin(proof).to_buf())?;
+    assert!(matches!(transport::parse(&mut extra.reader)?, Parsed::JoinRes(true)));
+    extra.writer.write_all(&Parsed::FileRange { id: 0, offset: 0 }.to_buf())?;
+    extra.writer.write_all(&Parsed::FileBlock { id: 0, data: noise(50_000, 13) }.to_buf())?;
+    drop(extra);
+
+    main.writer.write_all(&Parsed::FileEnd(vec![0; 32]).to_buf())?;
+    let events = lb.wait_done();
+    assert!(events.iter().any(|e| matches!(e, Event::Error { error: Error::Network(_), .. })));
+    let stored = lb.out.join("split.bin");
+    assert!(!stored.exists());
+    assert!(!paths::part_path(&stored).exists());
+    Ok(())
+}
+
+#[test]
+fn range_of_unsplit_file() -> io::Result<()> {
+    let dir = scratch("unsplit");
+    let lb = receiver(&dir, accept_all);
+    let data = noise(10_000, 17);
+
+    let mut main = encrypted(lb.addr)?;
+    let request = Parsed::AckReq {
+        files: vec![file_meta("main.bin", data.len() as u64)],
+        hashes: hash::SUPPORTED.to_vec(),
+        compressions: Vec::new(),
+    };
+    main.writer.write_all(&request.to_buf())?;
+    let algo = match transport::parse(&mut main.reader)? {
+        Parsed::AckRes { accept: true, hash, .. } => hash,
+        p => panic!("request not accepted: {:?}", p),
+    };
+    assert!(matches!(transport::parse(&mut main.reader)?, Parsed::ResumeInfo(_)));
+    main.writer.write_all(&Parsed::FileBlock { id: 0, data: data[..4000].to_vec() }.to_buf())?;
+
+    // the file comes over the main connection, an extra stream can't write into it
+    let mut extra = encrypted(lb.addr)?;
+    let proof = crypto::join_proof(&main.session.stream_key, &extra.session.binding);
+    extra.writer.write_all(&Parsed::StreamJoin(proof).to_buf())?;
+    assert!(matches!(transport::parse(&mut extra.reader)?, Parsed::JoinRes(true)));
+    extra.writer.write_all(&Parsed::FileRange { id: 0, offset: 0 }.to_buf())?;
+    extra.writer.write_all(&Parsed::FileBlock { id: 0, data: vec![0; 4000] }.to_buf())?;
+    let events = lb.wait_done();
+    assert!(events.iter().any(|e| matches!(e, Event::Error { .. })));
+
+    main.writer.write_all(&Parsed::FileBlock { id: 0, data: data[4000..].to_vec() }.to_buf())?;
+    let mut hasher = hash::Hasher::new(algo);
+    hasher.update(&data);
+    main.writer.write_all(&Parsed::FileEnd(hasher.finish()).to_buf())?;
+    // the request was seen by `wait_done` already
+    loop {
+        match lb.events.recv_timeout(TIMEOUT).expect("receiver didn't finish the file") {
+            Event::FileDone { .. } => break,
+            e @ Event::Error { .. } => panic!("{:?}", e),
+            _ => {}
+        }
+    }
+    assert!(std::fs::read(lb.out.join("main.bin"))? == data);
+    Ok(())
+}
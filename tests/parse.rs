@@ -0,0 +1,121 @@
+//! Properties of the packet parser: every packet comes back as it was written, and
+//! arbitrary bytes fail cleanly. `fuzz/` runs the second part with coverage guidance.
+
+use proptest::collection::vec;
+use proptest::prelude::*;
+use sfshare::compress::Compression;
+use sfshare::hash::HashAlgo;
+use sfshare::transport::{self, EntryKind, FileMeta, Hello, Limits, Parsed, PartialFile};
+
+use std::io::{BufRead, BufReader};
+use std::time::{Duration, UNIX_EPOCH};
+
+fn hash_algo() -> impl Strategy<Value = HashAlgo> {
+    prop_oneof![Just(HashAlgo::Additive), Just(HashAlgo::Sha256), Just(HashAlgo::Blake3)]
+}
+
+fn compression() -> impl Strategy<Value = Compression> {
+    prop_oneof![Just(Compression::None), Just(Compression::Lz4), Just(Compression::Zstd)]
+}
+
+fn hello() -> impl Strategy<Value = Hello> {
+    any::<(u16, u16, u32)>().prop_map(|(version, min_version, caps)| Hello {
+        version,
+        min_version,
+        caps,
+    })
+}
+
+fn file_meta() -> impl Strategy<Value = FileMeta> {
+    let kind = prop_oneof![Just(EntryKind::File), Just(EntryKind::Dir), Just(EntryKind::Symlink)];
+    (
+        // sizes stay below `Limits::max_total_size` together
+        (any::<u32>(), 0..1u64 << 40),
+        "\\PC{0,40}",
+        kind,
+        proptest::option::of((0..1u64 << 40, 0..1_000_000_000u32)),
+        proptest::option::of(any::<u32>()),
+        proptest::option::of("\\PC{0,40}"),
+    )
+        .prop_map(|((id, size), name, kind, mtime, mode, link)| FileMeta {
+            size,
+            id,
+            name,
+            kind,
+            path: None,
+            mtime: mtime.map(|(secs, nanos)| UNIX_EPOCH + Duration::new(secs, nanos)),
+            mode,
+            // symlinks always have a target
+            link: link.or_else(|| Some(String::from("target")).filter(|_| kind == EntryKind::Symlink)),
+        })
+}
+
+fn partial_file() -> impl Strategy<Value = PartialFile> {
+    (any::<(u32, u64)>(), vec(any::<u8>(), 0..=255)).prop_map(|((id, offset), digest)| PartialFile {
+        id,
+        offset,
+        digest,
+    })
+}
+
+/// every packet type, with lists and blocks short enough for many cases
+fn packet() -> impl Strategy<Value = Parsed> {
+    let data = || vec(any::<u8>(), 0..2000);
+    prop_oneof![
+        Just(Parsed::Ping),
+        hello().prop_map(Parsed::Hello),
+        hello().prop_map(Parsed::HelloRes),
+        any::<[u8; 32]>().prop_map(Parsed::KeyCommit),
+        any::<[u8; 32]>().prop_map(Parsed::KeyExchange),
+        (vec(file_meta(), 0..20), vec(hash_algo(), 0..5), vec(compression(), 0..5)).prop_map(
+            |(files, hashes, compressions)| Parsed::AckReq {
+                files,
+                hashes,
+                compressions,
+            }
+        ),
+        (any::<bool>(), hash_algo(), compression(), "\\PC{0,100}", vec(any::<(u32, bool)>(), 0..50)).prop_map(
+            |(accept, hash, compression, reason, files)| Parsed::AckRes {
+                accept,
+                hash,
+                compression,
+                reason,
+                files,
+            }
+        ),
+        vec(partial_file(), 0..20).prop_map(Parsed::ResumeInfo),
+        any::<[u8; 32]>().prop_map(Parsed::StreamJoin),
+        any::<bool>().prop_map(Parsed::JoinRes),
+        (any::<u32>(), data()).prop_map(|(id, data)| Parsed::FileBlock { id, data }),
+        (any::<u32>(), 0..=transport::MAX_BLOCK_SIZE as u32, data())
+            .prop_map(|(id, size, data)| Parsed::CompressedBlock { id, size, data }),
+        vec(any::<u8>(), 0..=255).prop_map(Parsed::FileEnd),
+        any::<(u32, u64)>().prop_map(|(id, offset)| Parsed::FileResume { id, offset }),
+        any::<(u32, u64)>().prop_map(|(id, offset)| Parsed::FileSplit { id, offset }),
+        any::<(u32, u64)>().prop_map(|(id, offset)| Parsed::FileRange { id, offset }),
+    ]
+}
+
+proptest! {
+    #[test]
+    fn round_trip(packets in vec(packet(), 1..10)) {
+        // packets follow each other on a connection, each one has to end where the next starts
+        let stream: Vec<u8> = packets.iter().flat_map(|p| p.to_buf().into_vec()).collect();
+        let mut reader = BufReader::new(&stream[..]);
+        for packet in &packets {
+            prop_assert_eq!(&transport::parse(&mut reader)?, packet);
+        }
+        prop_assert!(reader.fill_buf()?.is_empty());
+    }
+
+    #[test]
+    fn arbitrary_bytes(bytes in vec(any::<u8>(), 0..500)) {
+        let limits = Limits { max_entries: 100, max_block_size: 1000, ..Limits::default() };
+        let mut reader = BufReader::new(&bytes[..]);
+        // whatever parses writes back to the same packet
+        while let Ok(packet) = transport::parse_with(&mut reader, &limits) {
+            let again = transport::parse_with(&mut BufReader::new(&packet.to_buf()[..]), &limits)?;
+            prop_assert_eq!(again, packet);
+        }
+    }
+}